@@ -0,0 +1,126 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+// the pieces shared by the sequence CRDTs of the crate: identifiers of operations, version vectors,
+// changes of the visible sequence and the pseudo random numbers for the treap of woot and the allocation of logoot.
+
+// section 3.1, Data Model in the paper (https://hal.inria.fr/inria-00108523/document)
+// definition 3
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ID {
+    pub ns: i64, // the identifier of a site
+    pub ng: i64, // a logical clock
+}
+
+impl ID {
+    pub fn is_cb(&self) -> bool {
+        *self == CB_ID
+    }
+    pub fn is_ce(&self) -> bool {
+        *self == CE_ID
+    }
+}
+
+// total order on identifiers used to break ties between concurrent insertions.
+// cb is smaller and ce is larger than any other identifier,
+// and the others are ordered lexicographically by (site, clock).
+impl Ord for ID {
+    fn cmp(&self, other: &Self) -> Ordering {
+        if self == other {
+            Ordering::Equal
+        } else if self.is_cb() || other.is_ce() {
+            Ordering::Less
+        } else if self.is_ce() || other.is_cb() {
+            Ordering::Greater
+        } else {
+            (self.ns, self.ng).cmp(&(other.ns, other.ng))
+        }
+    }
+}
+
+impl PartialOrd for ID {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// the identifiers of cb and ce of WOOT, which the order puts before and after every other identifier
+pub(crate) const CB_ID: ID = ID {
+    ns: i64::MIN,
+    ng: 0,
+};
+
+pub(crate) const CE_ID: ID = ID {
+    ns: i64::MAX,
+    ng: 0,
+};
+
+// version vector: for each site, the clock up to which every operation of the site has been integrated
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VersionVector {
+    clocks: BTreeMap<i64, i64>,
+}
+
+impl VersionVector {
+    pub fn new() -> VersionVector {
+        VersionVector::default()
+    }
+
+    pub fn get(&self, site: i64) -> i64 {
+        self.clocks.get(&site).copied().unwrap_or(0)
+    }
+
+    pub fn set(&mut self, site: i64, clock: i64) {
+        self.clocks.insert(site, clock);
+    }
+
+    // whether the operation (or character) with id is covered
+    pub fn contains(&self, id: &ID) -> bool {
+        id.ng <= self.get(id.ns)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (i64, i64)> + '_ {
+        self.clocks.iter().map(|(&site, &clock)| (site, clock))
+    }
+
+    // take the maximum clock of each site
+    pub fn merge(&mut self, other: &VersionVector) {
+        for (site, clock) in other.iter() {
+            if clock > self.get(site) {
+                self.set(site, clock);
+            }
+        }
+    }
+}
+
+// a change of the visible sequence, in 0-based visible indices
+#[derive(Debug, Clone, PartialEq)]
+pub enum Patch<T = String> {
+    Insert { index: usize, values: Vec<T> },
+    Delete { index: usize, len: usize },
+}
+
+// xorshift64, a small deterministic pseudo random number generator.
+// the state must not be 0.
+#[derive(Debug, Clone)]
+pub struct XorShift(u64);
+
+impl XorShift {
+    pub fn new(seed: u64) -> XorShift {
+        XorShift(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    // a number in 0..n
+    pub fn next(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+}
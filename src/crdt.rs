@@ -0,0 +1,136 @@
+use std::fmt::Debug;
+
+use anyhow::Context;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::common::Patch;
+
+// the interface shared by the sequence CRDTs of the crate, so that the editor and the network layer
+// run on any of them. positions are visible positions counted in graphemes as in woot::Site:
+// an insertion at p goes between S[p-1] and S[p], and a deletion at p removes S[p] (1-based).
+pub trait SequenceCrdt {
+    type Operation: Clone + Debug + Serialize + DeserializeOwned;
+    // a position tied to an element instead of a visible index
+    type Anchor: Clone + Debug;
+
+    fn id(&self) -> i64;
+
+    // insert ch, which must be a single grapheme, between S[p-1] and S[p]
+    fn generate_ins(&mut self, p: usize, ch: &str) -> anyhow::Result<Self::Operation>;
+
+    fn generate_del(&mut self, p: usize) -> anyhow::Result<Self::Operation>;
+
+    // receive a remote operation, and returns the operations integrated by this call
+    fn execute(&mut self, operation: Self::Operation) -> anyhow::Result<Vec<Self::Operation>>;
+
+    fn text(&self) -> String;
+
+    // the number of visible elements
+    fn visible_len(&self) -> usize;
+
+    // the number of elements kept by the site including tombstones, to compare the cost of the algorithms
+    fn stored_len(&self) -> usize;
+
+    // the anchor sticking to S[p], or to the start if p is 0
+    fn anchor(&self, p: usize) -> Option<Self::Anchor>;
+
+    // the current visible position of the anchor
+    fn resolve(&self, anchor: &Self::Anchor) -> Option<usize>;
+
+    // revert the last local operation, or None if there is nothing to undo
+    fn undo(&mut self) -> anyhow::Result<Option<Self::Operation>> {
+        Ok(None)
+    }
+
+    fn redo(&mut self) -> anyhow::Result<Option<Self::Operation>> {
+        Ok(None)
+    }
+
+    // the visible changes made by the remote operations integrated since the last call (see woot::Site::take_patches).
+    // the algorithms which do not record them return nothing.
+    fn take_patches(&mut self) -> Vec<Patch> {
+        Vec::new()
+    }
+
+    // wire format of the operations
+    fn encode(operation: &Self::Operation) -> anyhow::Result<Vec<u8>> {
+        serde_json::to_vec(operation).context("cannot encode operation")
+    }
+
+    fn decode(bytes: &[u8]) -> anyhow::Result<Self::Operation> {
+        serde_json::from_slice(bytes).context("cannot decode operation")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SequenceCrdt;
+    use crate::testing::Edit::{Del, Ins};
+    use crate::testing::{random_op, Network, RangeEdits};
+    use crate::{logoot, rga, woot};
+
+    // three sites edit concurrently and receive the operations of the others in random order,
+    // through the wire format of the algorithm
+    fn run_trace<C: RangeEdits>(new_site: impl Fn(i64) -> C, seed: u64) -> Vec<C> {
+        let mut net: Network<C, Vec<u8>> = Network::new((1..=3).map(&new_site).collect(), seed);
+        let receive = |_: usize, site: &mut C, bytes: Vec<u8>| {
+            site.execute(C::decode(&bytes).unwrap()).unwrap();
+        };
+
+        for _ in 0..300 {
+            let i = net.pick();
+            if let Some(op) = random_op(&mut net.sites[i], &mut net.rng, &[Ins, Ins, Del]) {
+                net.broadcast(i, C::encode(&op).unwrap());
+            }
+            net.deliver_some(3, receive);
+        }
+        net.deliver_all(receive);
+        net.sites
+    }
+
+    fn assert_converged<C: SequenceCrdt>(sites: &[C]) {
+        for site in sites {
+            assert_eq!(site.text(), sites[0].text());
+            assert_eq!(site.visible_len(), sites[0].visible_len());
+            assert!(site.stored_len() >= site.visible_len());
+        }
+    }
+
+    #[test]
+    fn test_algorithms_converge() {
+        for seed in [88172645463325252, 2463534242] {
+            let woot_sites = run_trace(woot::new_site::<String>, seed);
+            let rga_sites = run_trace(rga::new_site, seed);
+            let logoot_sites = run_trace(logoot::new_site, seed);
+            assert_converged(&woot_sites);
+            assert_converged(&rga_sites);
+            assert_converged(&logoot_sites);
+            assert_eq!(logoot_sites[0].stored_len(), logoot_sites[0].visible_len());
+        }
+    }
+
+    // the same local edits give the same text on any algorithm
+    fn edit<C: SequenceCrdt>(site: &mut C) -> String {
+        for (p, ch) in ["h", "e", "l", "l", "o"].iter().enumerate() {
+            site.generate_ins(p + 1, ch).unwrap();
+        }
+        site.generate_del(2).unwrap();
+        site.generate_del(2).unwrap();
+        site.generate_ins(2, "x").unwrap();
+        let anchor = site.anchor(2).unwrap();
+        assert_eq!(site.resolve(&anchor), Some(2));
+        // positions out of the visible range are rejected
+        assert!(site.generate_ins(0, "y").is_err());
+        assert!(site.generate_ins(6, "y").is_err());
+        assert!(site.generate_del(5).is_err());
+        site.text()
+    }
+
+    #[test]
+    fn test_sequential_edits() {
+        assert_eq!(edit(&mut woot::new_site::<String>(1)), "hxlo");
+        assert_eq!(edit(&mut rga::new_site(1)), "hxlo");
+        assert_eq!(edit(&mut logoot::new_site(1)), "hxlo");
+    }
+}
@@ -1 +1,8 @@
+pub mod common;
+pub mod crdt;
+pub mod logoot;
+pub mod rga;
 pub mod woot;
+
+#[cfg(test)]
+mod testing;
@@ -0,0 +1,396 @@
+use std::collections::BTreeMap;
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::common::{VersionVector, XorShift, ID};
+use crate::crdt::SequenceCrdt;
+
+// Logoot in "Logoot: A Scalable Optimistic Replication Algorithm for Collaborative Editing on P2P Networks"
+// (https://hal.inria.fr/inria-00336191/document), with the allocation strategy of LSEQ
+// (https://hal.science/hal-00921633/document).
+// every character has a dense position identifier, and the characters are kept sorted by it.
+// a deletion removes the character, so no tombstone is kept.
+#[derive(Debug)]
+pub struct Site {
+    id: i64,
+    clock: i64,
+    chars: BTreeMap<Position, String>,
+    // operations received from remote sites which are not executable yet
+    pool: Vec<Operation>,
+    // operations integrated from each site
+    vv: VersionVector,
+    // generator for the allocation of digits
+    rng: XorShift,
+}
+
+pub fn new_site(id: i64) -> Site {
+    let mut vv = VersionVector::new();
+    vv.set(id, 0);
+    Site {
+        id,
+        clock: 0,
+        chars: BTreeMap::new(),
+        pool: Vec::new(),
+        vv,
+        rng: XorShift::new(0x2545_f491_4f6c_dd1d ^ id as u64),
+    }
+}
+
+// the digits of a level are in 1..BASE. 0 and BASE are the virtual bounds of the sequence.
+const BASE: u64 = 1 << 16;
+
+// the largest distance from a bound at which LSEQ allocates a digit
+const BOUNDARY: u64 = 10;
+
+// a level of a position: a digit, and the site and the clock of the insertion which made it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Identifier {
+    pub digit: u64,
+    pub site: i64,
+    pub clock: i64,
+}
+
+// position identifier, ordered lexicographically. the empty position is before every character.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize, Deserialize)]
+pub struct Position(pub Vec<Identifier>);
+
+impl Position {
+    // the insertion which made the position, which is the site and the clock of its last level
+    pub fn id(&self) -> Option<ID> {
+        self.0.last().map(|last| ID {
+            ns: last.site,
+            ng: last.clock,
+        })
+    }
+}
+
+// e.g. {"op":"INS","id":{..},"position":[{..}],"value":"a"}
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "op")]
+pub enum Operation {
+    #[serde(rename = "INS")]
+    Insert {
+        id: ID,
+        position: Position,
+        value: String,
+    },
+    // remove the character at the position
+    #[serde(rename = "DEL")]
+    Delete { id: ID, position: Position },
+}
+
+impl Operation {
+    pub fn id(&self) -> ID {
+        match self {
+            Operation::Insert { id, .. } | Operation::Delete { id, .. } => *id,
+        }
+    }
+}
+
+impl Site {
+    pub fn id(&self) -> i64 {
+        self.id
+    }
+
+    pub fn clock(&self) -> i64 {
+        self.clock
+    }
+
+    // the characters in order
+    pub fn chars(&self) -> impl Iterator<Item = (&Position, &String)> {
+        self.chars.iter()
+    }
+
+    pub fn text(&self) -> String {
+        self.chars.values().map(String::as_str).collect()
+    }
+
+    pub fn visible_len(&self) -> usize {
+        self.chars.len()
+    }
+
+    pub fn version_vector(&self) -> &VersionVector {
+        &self.vv
+    }
+
+    // the position of S[p], 1-based
+    fn ith(&self, p: usize) -> Option<&Position> {
+        if p == 0 {
+            return None;
+        }
+        self.chars.keys().nth(p - 1)
+    }
+
+    fn next_id(&mut self) -> ID {
+        self.clock += 1;
+        ID {
+            ns: self.id,
+            ng: self.clock,
+        }
+    }
+
+    // insert ch, which must be a single grapheme, between S[p-1] and S[p]
+    pub fn generate_ins(&mut self, p: usize, ch: &str) -> anyhow::Result<Operation> {
+        if ch.graphemes(true).count() != 1 {
+            bail!("{:?} is not a single grapheme", ch);
+        }
+        if p == 0 || p > self.visible_len() + 1 {
+            bail!("seq[{:?}] is out of bounds", p);
+        }
+        let prev = self.ith(p - 1).cloned().unwrap_or_default();
+        let next = self.ith(p).cloned();
+
+        let id = self.next_id();
+        let position = self.allocate(&prev, next.as_ref(), id);
+        let operation = Operation::Insert {
+            id,
+            position,
+            value: String::from(ch),
+        };
+        self.integrate(&operation);
+        Ok(operation)
+    }
+
+    pub fn generate_del(&mut self, p: usize) -> anyhow::Result<Operation> {
+        let position = self
+            .ith(p)
+            .cloned()
+            .context(format!("seq[{:?}] does not exist", p))?;
+        let id = self.next_id();
+        let operation = Operation::Delete { id, position };
+        self.integrate(&operation);
+        Ok(operation)
+    }
+
+    // a new position between prev and next (the end if None).
+    // a digit is taken near the lower bound on even levels and near the upper bound on odd ones,
+    // so that both appending and prepending keep the positions short.
+    // the last level of a position has a digit of 2 or more, which leaves room for a position before it.
+    fn allocate(&mut self, prev: &Position, next: Option<&Position>, id: ID) -> Position {
+        let mut levels = Vec::new();
+        // the bounds stop constraining the levels once the new position differs from them
+        let mut lower = Some(prev.0.as_slice());
+        let mut upper = next.map(|next| next.0.as_slice());
+        for depth in 0.. {
+            let low = lower.and_then(|l| l.get(depth));
+            let high = upper.and_then(|u| u.get(depth));
+            let a = low.map_or(0, |l| l.digit);
+            let b = high.map_or(BASE, |u| u.digit);
+            let level = |digit| Identifier {
+                digit,
+                site: id.ns,
+                clock: id.ng,
+            };
+            if b > a + 1 && b > 2 {
+                let (min, max) = ((a + 1).max(2), b - 1);
+                let step = self.rng.next_u64() % (max - min + 1).min(BOUNDARY);
+                let digit = if depth % 2 == 0 {
+                    min + step
+                } else {
+                    max - step
+                };
+                levels.push(level(digit));
+                break;
+            }
+            if b > a + 1 {
+                // only the digit 1 fits below the bound 2, which is between the bounds,
+                // so the levels below it are free
+                levels.push(level(1));
+                lower = None;
+                upper = None;
+                continue;
+            }
+
+            // no room between the bounds: copy the level of prev, or of next once prev has run out of levels.
+            // next then has more levels, as the digit of its last level is not 1.
+            let copied = *low.or(high).expect("one of the bounds has a level");
+            if low != Some(&copied) {
+                lower = None;
+            }
+            if high != Some(&copied) {
+                upper = None;
+            }
+            levels.push(copied);
+        }
+        Position(levels)
+    }
+
+    // receive a remote operation. the operations of each site are integrated in the order of their clocks,
+    // and a deletion waits for the insertion of its character.
+    // returns the operations integrated by this call (empty for duplicates).
+    pub fn execute(&mut self, operation: Operation) -> anyhow::Result<Vec<Operation>> {
+        if self.vv.contains(&operation.id()) || self.pool.iter().any(|op| op.id() == operation.id())
+        {
+            return Ok(Vec::new());
+        }
+        if let Operation::Insert { id, position, .. } = &operation {
+            if position.id() != Some(*id) {
+                bail!("position {:?} was not made by {:?}", position, id);
+            }
+        }
+        self.pool.push(operation);
+
+        let mut integrated = Vec::new();
+        while let Some(i) = self.pool.iter().position(|op| self.is_executable(op)) {
+            let operation = self.pool.remove(i);
+            self.integrate(&operation);
+            integrated.push(operation);
+        }
+        Ok(integrated)
+    }
+
+    // the number of operations waiting in the pool
+    pub fn pending(&self) -> usize {
+        self.pool.len()
+    }
+
+    pub fn is_executable(&self, operation: &Operation) -> bool {
+        let id = operation.id();
+        if id.ng != self.vv.get(id.ns) + 1 {
+            return false;
+        }
+        match operation {
+            Operation::Insert { .. } => true,
+            Operation::Delete { position, .. } => position
+                .id()
+                .is_some_and(|insert| self.vv.contains(&insert)),
+        }
+    }
+
+    fn integrate(&mut self, operation: &Operation) {
+        match operation {
+            Operation::Insert {
+                position, value, ..
+            } => {
+                self.chars.insert(position.clone(), value.clone());
+            }
+            // the character may have been removed by a concurrent deletion
+            Operation::Delete { position, .. } => {
+                self.chars.remove(position);
+            }
+        }
+        let id = operation.id();
+        self.vv.set(id.ns, id.ng);
+    }
+
+    // the anchor right after S[p], which is the empty position if p is 0
+    pub fn anchor(&self, p: usize) -> Option<Position> {
+        match p {
+            0 => Some(Position::default()),
+            _ => self.ith(p).cloned(),
+        }
+    }
+
+    // the number of characters up to the anchor. positions are dense, so it stays meaningful
+    // after its character is removed.
+    pub fn resolve(&self, anchor: &Position) -> Option<usize> {
+        Some(self.chars.range(..=anchor).count())
+    }
+}
+
+impl SequenceCrdt for Site {
+    type Operation = Operation;
+    type Anchor = Position;
+
+    fn id(&self) -> i64 {
+        self.id
+    }
+
+    fn generate_ins(&mut self, p: usize, ch: &str) -> anyhow::Result<Operation> {
+        self.generate_ins(p, ch)
+    }
+
+    fn generate_del(&mut self, p: usize) -> anyhow::Result<Operation> {
+        self.generate_del(p)
+    }
+
+    fn execute(&mut self, operation: Operation) -> anyhow::Result<Vec<Operation>> {
+        self.execute(operation)
+    }
+
+    fn text(&self) -> String {
+        self.text()
+    }
+
+    fn visible_len(&self) -> usize {
+        self.visible_len()
+    }
+
+    fn stored_len(&self) -> usize {
+        self.chars.len()
+    }
+
+    fn anchor(&self, p: usize) -> Option<Position> {
+        self.anchor(p)
+    }
+
+    fn resolve(&self, anchor: &Position) -> Option<usize> {
+        self.resolve(anchor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::new_site;
+
+    #[test]
+    fn test_positions() {
+        let mut site1 = new_site(1);
+        let mut site2 = new_site(2);
+
+        // appending and prepending many characters keeps the positions short
+        let mut ops = Vec::new();
+        let mut expected = String::new();
+        for i in 1..=200 {
+            ops.push(site1.generate_ins(i, "a").unwrap());
+            expected.insert(i - 1, 'a');
+            ops.push(site1.generate_ins(1, "b").unwrap());
+            expected.insert(0, 'b');
+        }
+        assert_eq!(site1.text(), expected);
+        assert!(site1.chars().all(|(position, _)| position.0.len() <= 4));
+
+        // the characters typed before or after all the others stay in the typed order
+        let mut site3 = new_site(3);
+        let mut site4 = new_site(4);
+        let typed: Vec<String> = (0..60).map(|i| char::from(b'0' + i).to_string()).collect();
+        for (i, ch) in typed.iter().enumerate() {
+            site3.generate_ins(1, ch).unwrap();
+            site4.generate_ins(i + 1, ch).unwrap();
+        }
+        let reversed: String = typed.iter().rev().map(String::as_str).collect();
+        assert_eq!(site3.text(), reversed);
+        assert_eq!(site4.text(), typed.concat());
+
+        // concurrent insertions at the same place, and concurrent deletions of the same character
+        let del = site1.generate_del(200).unwrap();
+        for op in ops.iter().cloned().chain([del]) {
+            site2.execute(op).unwrap();
+        }
+        let ins1 = site1.generate_ins(200, "x").unwrap();
+        let ins2 = site2.generate_ins(200, "y").unwrap();
+        let del1 = site1.generate_del(1).unwrap();
+        let del2 = site2.generate_del(1).unwrap();
+        for op in [ins2, del2] {
+            site1.execute(op).unwrap();
+        }
+        // a deletion waits for the insertion of its character
+        assert!(site2.execute(del1.clone()).unwrap().is_empty());
+        assert_eq!(site2.pending(), 1);
+        assert_eq!(site2.execute(ins1).unwrap().len(), 2);
+        assert!(site2.execute(del1).unwrap().is_empty());
+
+        assert_eq!(site1.text(), site2.text());
+        assert_eq!(site1.visible_len(), 400);
+        // no tombstones are kept
+        assert_eq!(site1.chars().count(), site1.visible_len());
+
+        let anchor = site1.anchor(2).unwrap();
+        site1.generate_del(2).unwrap();
+        assert_eq!(site1.resolve(&anchor), Some(1));
+        assert!(site1.generate_ins(1, "ab").is_err());
+        assert!(site1.generate_del(500).is_err());
+    }
+}
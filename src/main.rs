@@ -1,22 +1,27 @@
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
 use crossterm::cursor::EnableBlinking;
 use crossterm::event::DisableMouseCapture;
 use crossterm::terminal::{
-    disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen, SetSize,
+    disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
 };
 use ratatui::backend::CrosstermBackend;
 use ratatui::layout::{Constraint, Direction, Layout};
 use ratatui::widgets::Paragraph;
 use ratatui::Terminal;
-use serde::Deserialize;
-use std::io::Write;
+use std::fs::File;
+use std::io::{Read, Write};
 use std::net::{TcpListener, TcpStream};
+use std::path::Path;
 use std::sync::{mpsc, Arc, Mutex};
 use std::time::Duration;
 use std::{env, io, thread};
 use tui_textarea::{Input, Key};
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
 
+use toywoot::crdt::SequenceCrdt;
 use toywoot::woot::{self};
+use toywoot::{logoot, rga};
 
 fn connect(ip: &str, port: u16) -> anyhow::Result<TcpStream> {
     for _ in 0..10 {
@@ -31,6 +36,18 @@ fn connect(ip: &str, port: u16) -> anyhow::Result<TcpStream> {
 
     bail!("connect error");
 }
+
+// send the operation to the remote site in the background
+fn send<C: SequenceCrdt>(operation: C::Operation, to: u16, delay: u64) {
+    let op = C::encode(&operation).unwrap();
+    thread::spawn(move || {
+        // connect
+        let mut stream = connect("127.0.0.1", to).unwrap();
+
+        thread::sleep(Duration::from_secs(delay));
+        stream.write_all(&op).expect("can send");
+    });
+}
 fn main() -> Result<()> {
     env::set_var("RUST_LOG", "error");
     env_logger::Builder::from_default_env()
@@ -59,10 +76,53 @@ fn main() -> Result<()> {
         delay = args[4].parse::<u64>().unwrap_or(0);
     }
 
+    // the site is resumed from the snapshot file if it exists, and saved to it on exit
+    let snapshot = args.get(5).cloned();
+
     // listen
     let listener = TcpListener::bind(("127.0.0.1", from)).unwrap();
 
-    let mut px: usize = 0;
+    // the algorithm is chosen by TOYWOOT_CRDT, which is woot (default), rga or logoot.
+    // every site editing the document has to use the same one.
+    match env::var("TOYWOOT_CRDT").as_deref() {
+        Ok("rga") => {
+            if snapshot.is_some() {
+                bail!("snapshots are supported only by woot");
+            }
+            run(rga::new_site(site_id), listener, to, delay)?;
+        }
+        Ok("logoot") => {
+            if snapshot.is_some() {
+                bail!("snapshots are supported only by woot");
+            }
+            run(logoot::new_site(site_id), listener, to, delay)?;
+        }
+        Ok("woot") | Err(_) => {
+            let initial_site = match &snapshot {
+                Some(path) if Path::new(path).exists() => woot::load_site(File::open(path)?)?,
+                _ => woot::new_site(site_id),
+            };
+            if initial_site.id() != site_id {
+                bail!("the snapshot belongs to site {}", initial_site.id());
+            }
+
+            let site = run(initial_site, listener, to, delay)?;
+            if let Some(path) = snapshot {
+                site.lock().unwrap().save(File::create(path)?)?;
+            }
+        }
+        Ok(other) => bail!("unknown algorithm {:?}", other),
+    }
+    Ok(())
+}
+
+// edit the document on the terminal until Esc is pressed.
+// returns the site, which is still shared with the receive thread.
+fn run<C>(initial_site: C, listener: TcpListener, to: u16, delay: u64) -> Result<Arc<Mutex<C>>>
+where
+    C: SequenceCrdt + Send + 'static,
+    C::Operation: Send,
+{
     // settings for crossterm
     let stdout = io::stdout();
     let mut stdout = stdout.lock();
@@ -89,20 +149,22 @@ fn main() -> Result<()> {
     });
 
     // receive thread
-    let site = Arc::new(Mutex::new(woot::new_site(site_id, 0)));
+    // the cursor sticks to the character before it, so that remote edits do not move it onto another one
+    let mut cursor = initial_site.anchor(0).context("empty sequence")?;
+    let site = Arc::new(Mutex::new(initial_site));
 
     let s0 = Arc::clone(&site);
     let s1 = Arc::clone(&site);
-    let s2 = Arc::clone(&site);
     let mut error_message = String::new();
 
     thread::spawn(move || {
         // key event from remote
         for stream in listener.incoming() {
-            let stream = stream.unwrap();
+            let mut stream = stream.unwrap();
 
-            let mut de = serde_json::Deserializer::from_reader(stream);
-            let op = woot::Operation::deserialize(&mut de).unwrap();
+            let mut buf = Vec::new();
+            stream.read_to_end(&mut buf).unwrap();
+            let op = C::decode(&buf).unwrap();
 
             log::info!("receive {:?}", op);
 
@@ -113,9 +175,16 @@ fn main() -> Result<()> {
                     // ignore
                     eprintln!("operation from remote failed {:?}", e);
                 }
-                Ok(_) => {
-                    // noop
-                    log::info!("recive remote op -> text: {:?}", s.seq.text());
+                Ok(integrated) => {
+                    // the operation may wait in the pool until its dependencies arrive.
+                    // the text is rendered again anyway, but the patches are drained so that they do not pile up.
+                    let patches = s.take_patches();
+                    log::info!(
+                        "recive remote op -> integrated: {:?}, stored: {:?}, patches: {:?}",
+                        integrated.len(),
+                        s.stored_len(),
+                        patches
+                    );
                     drop(s);
 
                     // for refreshing the terminal
@@ -139,14 +208,23 @@ fn main() -> Result<()> {
                 .constraints([Constraint::Length(1), Constraint::Percentage(10)].as_ref())
                 .split(f.size());
             let s = s1.lock().unwrap();
-            let text = Paragraph::new(s.seq.text());
-            f.render_widget(text, chunks[0]);
-            f.render_widget(Paragraph::new(format!("error: ",)), chunks[1]);
-            f.set_cursor(px as u16, 0);
+            let text = s.text();
+            let px = s.resolve(&cursor).unwrap_or(0);
+            // px counts graphemes, and the cursor is placed at the display width of the ones before it
+            let column: usize = text.graphemes(true).take(px).map(|g| g.width()).sum();
+            f.render_widget(Paragraph::new(text), chunks[0]);
+            f.render_widget(
+                Paragraph::new(format!("error: {}", error_message)),
+                chunks[1],
+            );
+            f.set_cursor(column as u16, 0);
             drop(s);
         })?;
 
-        match rx.recv()? {
+        let input = rx.recv()?;
+        // the visible position of the cursor, which the keys below edit and move
+        let mut px = s1.lock().unwrap().resolve(&cursor).unwrap_or(0);
+        match input {
             Input { key: Key::Esc, .. } => {
                 break;
             }
@@ -174,19 +252,10 @@ fn main() -> Result<()> {
                         // noop
                         error_message.clear();
 
-                        thread::spawn(move || {
-                            // connect
-                            let mut stream = connect("127.0.0.1", to).unwrap();
-
-                            let del = serde_json::to_string(&operation).unwrap();
-                            thread::sleep(Duration::from_secs(delay));
-                            stream.write_all(del.as_bytes()).expect("can send");
-                        });
+                        send::<C>(operation, to, delay);
                     }
                 }
-                if px > 0 {
-                    px -= 1;
-                }
+                px = px.saturating_sub(1);
             }
             Input {
                 key: Key::Enter, ..
@@ -194,48 +263,55 @@ fn main() -> Result<()> {
                 // noop
             }
             Input { key: Key::Left, .. } => {
-                if px > 0 {
-                    px -= 1;
-                }
+                px = px.saturating_sub(1);
             }
             Input {
                 key: Key::Char('b'),
                 ctrl: true,
                 ..
             } => {
-                if px > 0 {
-                    px -= 1;
-                }
+                px = px.saturating_sub(1);
             }
             Input {
                 key: Key::Char('f'),
                 ctrl: true,
                 ..
             } => {
-                px += 1;
                 let s = s1.lock().unwrap();
-                let len = s.seq.text().len();
-                if px > len {
-                    px = len;
-                }
+                px = (px + 1).min(s.visible_len());
             }
             Input {
                 key: Key::Right, ..
             } => {
                 let s = s1.lock().unwrap();
-                px += 1;
-                let len = s.seq.text().len();
-                if px > len {
-                    px = len;
+                px = (px + 1).min(s.visible_len());
+                drop(s);
+            }
+            Input {
+                key: Key::Char(key @ ('z' | 'y')),
+                ctrl: true,
+                ..
+            } => {
+                let mut s = s1.lock().unwrap();
+                let result = if key == 'z' { s.undo() } else { s.redo() };
+                match result {
+                    Err(e) => {
+                        error_message = e.to_string();
+                    }
+                    Ok(operation) => {
+                        error_message.clear();
+                        if let Some(operation) = operation {
+                            send::<C>(operation, to, delay);
+                        }
+                    }
                 }
                 drop(s);
             }
             Input { key, .. } => {
-                px += 1;
                 for ch in 'a'..='z' {
                     if key == Key::Char(ch) {
                         let mut s = s1.lock().unwrap();
-                        match s.generate_ins(px as usize, &ch.to_string()) {
+                        match s.generate_ins(px + 1, &ch.to_string()) {
                             Err(e) => {
                                 drop(s);
                                 eprintln!("{:?}", e);
@@ -244,15 +320,9 @@ fn main() -> Result<()> {
                                 drop(s);
                                 // noop
                                 error_message.clear();
+                                px += 1;
 
-                                thread::spawn(move || {
-                                    // connect
-                                    let mut stream = connect("127.0.0.1", to).unwrap();
-
-                                    let del = serde_json::to_string(&operation).unwrap();
-                                    thread::sleep(Duration::from_secs(delay));
-                                    stream.write_all(del.as_bytes()).expect("can send");
-                                });
+                                send::<C>(operation, to, delay);
                             }
                         }
                         break;
@@ -260,6 +330,12 @@ fn main() -> Result<()> {
                 }
             }
         }
+
+        let s = s1.lock().unwrap();
+        if let Some(anchor) = s.anchor(px) {
+            cursor = anchor;
+        }
+        drop(s);
     }
 
     disable_raw_mode()?;
@@ -270,8 +346,7 @@ fn main() -> Result<()> {
     )?;
     term.show_cursor()?;
 
-    log::info!("px: {:?}", px);
-    let s = s2.lock().unwrap();
-    log::info!("text: {:?}", s.seq.text());
-    Ok(())
+    log::info!("cursor: {:?}", cursor);
+    log::info!("text: {:?}", s1.lock().unwrap().text());
+    Ok(site)
 }
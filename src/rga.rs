@@ -0,0 +1,310 @@
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::crdt::SequenceCrdt;
+
+// Replicated Growable Array in "Replicated abstract data types: Building blocks for collaborative applications"
+// (https://doi.org/10.1016/j.jpdc.2010.12.006)
+// every element is inserted after an existing one (or the start), and the concurrent insertions after
+// the same element are ordered by their timestamps, the larger first. deleted elements stay as tombstones.
+#[derive(Debug)]
+pub struct Site {
+    id: i64,
+    // lamport clock: the largest clock seen so far
+    clock: i64,
+    elements: Vec<Element>,
+    // operations received from remote sites which are not executable yet
+    pool: Vec<Operation>,
+}
+
+pub fn new_site(id: i64) -> Site {
+    Site {
+        id,
+        clock: 0,
+        elements: Vec::new(),
+        pool: Vec::new(),
+    }
+}
+
+// the identifier of an element. ordered by the clock first,
+// so an insertion is larger than every insertion its site had seen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Timestamp {
+    pub clock: i64,
+    pub site: i64,
+}
+
+// the start of the sequence, which is smaller than any timestamp
+pub const ROOT: Timestamp = Timestamp {
+    clock: 0,
+    site: i64::MIN,
+};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Element {
+    pub id: Timestamp,
+    pub value: String,
+    pub visible: bool,
+}
+
+// e.g. {"op":"INS","id":{..},"after":{..},"value":"a"}
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "op")]
+pub enum Operation {
+    // insert value right after the element after (or at the start if it is ROOT)
+    #[serde(rename = "INS")]
+    Insert {
+        id: Timestamp,
+        after: Timestamp,
+        value: String,
+    },
+    // hide the element with id
+    #[serde(rename = "DEL")]
+    Delete { id: Timestamp },
+}
+
+impl Site {
+    pub fn id(&self) -> i64 {
+        self.id
+    }
+
+    pub fn elements(&self) -> &[Element] {
+        &self.elements
+    }
+
+    pub fn text(&self) -> String {
+        self.elements
+            .iter()
+            .filter(|e| e.visible)
+            .map(|e| e.value.as_str())
+            .collect()
+    }
+
+    pub fn visible_len(&self) -> usize {
+        self.elements.iter().filter(|e| e.visible).count()
+    }
+
+    // the index of the element with id in elements
+    fn index(&self, id: &Timestamp) -> Option<usize> {
+        self.elements.iter().position(|e| e.id == *id)
+    }
+
+    // S[p], 1-based among the visible elements
+    fn ith_visible(&self, p: usize) -> Option<&Element> {
+        if p == 0 {
+            return None;
+        }
+        self.elements.iter().filter(|e| e.visible).nth(p - 1)
+    }
+
+    // insert ch, which must be a single grapheme, between S[p-1] and S[p]
+    pub fn generate_ins(&mut self, p: usize, ch: &str) -> anyhow::Result<Operation> {
+        if ch.graphemes(true).count() != 1 {
+            bail!("{:?} is not a single grapheme", ch);
+        }
+        if p == 0 || p > self.visible_len() + 1 {
+            bail!("seq[{:?}] is out of bounds", p);
+        }
+        let after = match p {
+            1 => ROOT,
+            _ => self.ith_visible(p - 1).context("should be visible")?.id,
+        };
+
+        self.clock += 1;
+        let operation = Operation::Insert {
+            id: Timestamp {
+                clock: self.clock,
+                site: self.id,
+            },
+            after,
+            value: String::from(ch),
+        };
+        self.integrate(&operation)?;
+        Ok(operation)
+    }
+
+    pub fn generate_del(&mut self, p: usize) -> anyhow::Result<Operation> {
+        let c = self
+            .ith_visible(p)
+            .context(format!("seq[{:?}] does not exist or is not visible", p))?;
+        let operation = Operation::Delete { id: c.id };
+        self.integrate(&operation)?;
+        Ok(operation)
+    }
+
+    // receive a remote operation. it waits in the pool until the element it refers to arrives.
+    // returns the operations integrated by this call.
+    pub fn execute(&mut self, operation: Operation) -> anyhow::Result<Vec<Operation>> {
+        // insertions already integrated are no-ops, and deletions are idempotent anyway
+        if let Operation::Insert { id, .. } = &operation {
+            if self.index(id).is_some() || self.pool.contains(&operation) {
+                return Ok(Vec::new());
+            }
+        }
+        self.pool.push(operation);
+
+        let mut integrated = Vec::new();
+        while let Some(i) = self.pool.iter().position(|op| self.is_executable(op)) {
+            let operation = self.pool.remove(i);
+            self.integrate(&operation)?;
+            integrated.push(operation);
+        }
+        Ok(integrated)
+    }
+
+    // the number of operations waiting in the pool
+    pub fn pending(&self) -> usize {
+        self.pool.len()
+    }
+
+    pub fn is_executable(&self, operation: &Operation) -> bool {
+        match operation {
+            Operation::Insert { after, .. } => *after == ROOT || self.index(after).is_some(),
+            Operation::Delete { id } => self.index(id).is_some(),
+        }
+    }
+
+    fn integrate(&mut self, operation: &Operation) -> anyhow::Result<()> {
+        match operation {
+            Operation::Insert { id, after, value } => {
+                let mut i = match *after {
+                    ROOT => 0,
+                    _ => {
+                        self.index(after)
+                            .context(format!("cannot find {:?}", after))?
+                            + 1
+                    }
+                };
+                // skip the concurrent insertions after the same element which are larger,
+                // together with the elements inserted after them (which are larger still)
+                while i < self.elements.len() && self.elements[i].id > *id {
+                    i += 1;
+                }
+                self.elements.insert(
+                    i,
+                    Element {
+                        id: *id,
+                        value: value.clone(),
+                        visible: true,
+                    },
+                );
+                self.clock = self.clock.max(id.clock);
+            }
+            Operation::Delete { id } => {
+                let i = self.index(id).context(format!("cannot find {:?}", id))?;
+                self.elements[i].visible = false;
+            }
+        }
+        Ok(())
+    }
+
+    // the anchor sticking to S[p], or ROOT if p is 0
+    pub fn anchor(&self, p: usize) -> Option<Timestamp> {
+        match p {
+            0 => Some(ROOT),
+            _ => Some(self.ith_visible(p)?.id),
+        }
+    }
+
+    // the visible position right after the element of the anchor, even if it has been deleted
+    pub fn resolve(&self, anchor: &Timestamp) -> Option<usize> {
+        if *anchor == ROOT {
+            return Some(0);
+        }
+        let i = self.index(anchor)?;
+        Some(self.elements[..=i].iter().filter(|e| e.visible).count())
+    }
+}
+
+impl SequenceCrdt for Site {
+    type Operation = Operation;
+    type Anchor = Timestamp;
+
+    fn id(&self) -> i64 {
+        self.id
+    }
+
+    fn generate_ins(&mut self, p: usize, ch: &str) -> anyhow::Result<Operation> {
+        self.generate_ins(p, ch)
+    }
+
+    fn generate_del(&mut self, p: usize) -> anyhow::Result<Operation> {
+        self.generate_del(p)
+    }
+
+    fn execute(&mut self, operation: Operation) -> anyhow::Result<Vec<Operation>> {
+        self.execute(operation)
+    }
+
+    fn text(&self) -> String {
+        self.text()
+    }
+
+    fn visible_len(&self) -> usize {
+        self.visible_len()
+    }
+
+    fn stored_len(&self) -> usize {
+        self.elements.len()
+    }
+
+    fn anchor(&self, p: usize) -> Option<Timestamp> {
+        self.anchor(p)
+    }
+
+    fn resolve(&self, anchor: &Timestamp) -> Option<usize> {
+        self.resolve(anchor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::new_site;
+
+    #[test]
+    fn test_concurrent_insert() {
+        let mut site1 = new_site(1);
+        let mut site2 = new_site(2);
+        let mut site3 = new_site(3);
+
+        let a = site1.generate_ins(1, "a").unwrap();
+        let b = site1.generate_ins(2, "b").unwrap();
+        let del_a = site1.generate_del(1).unwrap();
+
+        // site2 receives the operations in reverse order
+        assert!(site2.execute(del_a.clone()).unwrap().is_empty());
+        assert!(site2.execute(b.clone()).unwrap().is_empty());
+        assert_eq!(site2.execute(a.clone()).unwrap().len(), 3);
+        assert_eq!(site2.text(), "b");
+        for op in [a, b, del_a] {
+            site3.execute(op).unwrap();
+        }
+
+        // concurrent insertions at the start: the later timestamp goes first
+        let x = site2.generate_ins(1, "x").unwrap();
+        let y = site3.generate_ins(1, "y").unwrap();
+        let z = site3.generate_ins(2, "z").unwrap();
+        for op in [y.clone(), z.clone()] {
+            site2.execute(op.clone()).unwrap();
+            site1.execute(op).unwrap();
+        }
+        site3.execute(x.clone()).unwrap();
+        site1.execute(x.clone()).unwrap();
+        // duplicates are ignored
+        assert!(site1.execute(x).unwrap().is_empty());
+
+        assert_eq!(site1.text(), "yzxb");
+        assert_eq!(site2.text(), site1.text());
+        assert_eq!(site3.text(), site1.text());
+
+        let anchor = site1.anchor(2).unwrap();
+        site1.execute(site2.generate_ins(1, "w").unwrap()).unwrap();
+        assert_eq!(site1.resolve(&anchor), Some(3));
+        site1.generate_del(3).unwrap();
+        assert_eq!(site1.resolve(&anchor), Some(2));
+
+        assert!(site1.generate_ins(1, "ab").is_err());
+        assert!(site1.generate_ins(6, "a").is_err());
+    }
+}
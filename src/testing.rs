@@ -0,0 +1,117 @@
+// helpers shared by the tests which let several sites edit concurrently
+
+use crate::common::XorShift;
+use crate::crdt::SequenceCrdt;
+use crate::{logoot, rga, woot};
+
+// sites connected by inboxes, which receive the messages of the others in random order
+pub struct Network<S, M> {
+    pub sites: Vec<S>,
+    pub rng: XorShift,
+    inboxes: Vec<Vec<M>>,
+}
+
+impl<S, M: Clone> Network<S, M> {
+    pub fn new(sites: Vec<S>, seed: u64) -> Self {
+        let inboxes = sites.iter().map(|_| Vec::new()).collect();
+        Network {
+            sites,
+            rng: XorShift::new(seed),
+            inboxes,
+        }
+    }
+
+    // a random site to edit next
+    pub fn pick(&mut self) -> usize {
+        self.rng.next(self.sites.len())
+    }
+
+    // send the message of the site i to every other site
+    pub fn broadcast(&mut self, i: usize, message: M) {
+        for (j, inbox) in self.inboxes.iter_mut().enumerate() {
+            if j != i {
+                inbox.push(message.clone());
+            }
+        }
+    }
+
+    // deliver fewer than n messages taken at random, as receive(j, site j, message)
+    pub fn deliver_some(&mut self, n: usize, mut receive: impl FnMut(usize, &mut S, M)) {
+        for _ in 0..self.rng.next(n) {
+            let j = self.rng.next(self.sites.len());
+            if !self.inboxes[j].is_empty() {
+                let k = self.rng.next(self.inboxes[j].len());
+                let message = self.inboxes[j].remove(k);
+                receive(j, &mut self.sites[j], message);
+            }
+        }
+    }
+
+    // deliver every message left, in random order for each site
+    pub fn deliver_all(&mut self, mut receive: impl FnMut(usize, &mut S, M)) {
+        for (j, inbox) in self.inboxes.iter_mut().enumerate() {
+            while !inbox.is_empty() {
+                let k = self.rng.next(inbox.len());
+                receive(j, &mut self.sites[j], inbox.remove(k));
+            }
+        }
+    }
+}
+
+// the kinds of edits made by random_op
+#[derive(Clone, Copy)]
+pub enum Edit {
+    // insert "a"
+    Ins,
+    // insert "bc" as one operation
+    InsertStr,
+    Del,
+    // delete two characters as one operation
+    DeleteRange,
+    Undo,
+    Redo,
+}
+
+// the operations on several characters at once, which only woot generates
+pub trait RangeEdits: SequenceCrdt {
+    fn insert_str(&mut self, _p: usize, _text: &str) -> Option<Self::Operation> {
+        None
+    }
+
+    fn delete_range(&mut self, _p: usize, _len: usize) -> Option<Self::Operation> {
+        None
+    }
+}
+
+impl RangeEdits for woot::Site {
+    fn insert_str(&mut self, p: usize, text: &str) -> Option<woot::Operation> {
+        Some(self.generate_insert_str(p, text).unwrap())
+    }
+
+    fn delete_range(&mut self, p: usize, len: usize) -> Option<woot::Operation> {
+        Some(self.generate_delete_range(p, len).unwrap())
+    }
+}
+
+impl RangeEdits for rga::Site {}
+
+impl RangeEdits for logoot::Site {}
+
+// an edit of the site at a random position, of a kind picked from mix (repeat a kind to make it more likely).
+// None if the site can not make it, e.g. a deletion of an empty text or an undo without anything to undo.
+pub fn random_op<S: RangeEdits>(
+    site: &mut S,
+    rng: &mut XorShift,
+    mix: &[Edit],
+) -> Option<S::Operation> {
+    let len = site.visible_len();
+    match mix[rng.next(mix.len())] {
+        Edit::Ins => Some(site.generate_ins(1 + rng.next(len + 1), "a").unwrap()),
+        Edit::InsertStr => site.insert_str(1 + rng.next(len + 1), "bc"),
+        Edit::Del if len > 0 => Some(site.generate_del(1 + rng.next(len)).unwrap()),
+        Edit::DeleteRange if len > 1 => site.delete_range(1 + rng.next(len - 1), 2),
+        Edit::Undo => site.undo().unwrap(),
+        Edit::Redo => site.redo().unwrap(),
+        _ => None,
+    }
+}
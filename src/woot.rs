@@ -1,415 +1,3693 @@
-use std::collections::LinkedList;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Read, Write};
+use std::ops::{Range, RangeInclusive};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use anyhow::{anyhow, bail, Context, Error};
+use anyhow::{bail, Context};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use unicode_segmentation::UnicodeSegmentation;
 
+pub use crate::common::{Patch, VersionVector, ID};
+use crate::common::{XorShift, CB_ID, CE_ID};
+use crate::crdt::SequenceCrdt;
+
+// a site editing a sequence of elements of type T, which is text (a sequence of strings) by default.
+// the elements are opaque to WOOT: T::default() is only the payload of cb and ce.
 #[derive(Debug)]
-pub struct Site {
+pub struct Site<T = String> {
     id: i64,
     clock: i64,
-    pub seq: Sequence,
+    pub seq: Sequence<T>,
+    // operations received from remote sites which are not executable yet
+    pool: Vec<Operation<T>>,
+    // local operations which can be undone, and undone ones which can be redone
+    undo_stack: Vec<Operation<T>>,
+    redo_stack: Vec<Operation<T>>,
+    // operations integrated from each site
+    vv: VersionVector,
+    // the latest version vector acknowledged by each peer
+    acks: HashMap<i64, VersionVector>,
+    // the operations which changed the visibility degree of each tombstone
+    touched: HashMap<ID, Vec<ID>>,
+    // every operation integrated by this site in the order of integration
+    log: Vec<LogEntry<T>>,
+    // changes of the visible sequence made by remote operations, until they are taken
+    patches: Vec<Patch<T>>,
+    integration: Integration,
+}
+
+// the algorithm placing an inserted character among the concurrent ones.
+// both give the same sequence for the same operations.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Integration {
+    // IntegrateIns of WOOT, which keeps the characters whose bounds are outside the range at each step
+    #[default]
+    Classic,
+    // IntegrateIns of WOOTO, which keeps the characters with the smallest level instead
+    Wooto,
+}
+
+// resume a site from a snapshot written by Site::save
+pub fn load_site<T, R>(reader: R) -> anyhow::Result<Site<T>>
+where
+    T: Clone + Default + DeserializeOwned,
+    R: Read,
+{
+    let snapshot: SiteSnapshot<T> =
+        serde_json::from_reader(reader).context("cannot read snapshot")?;
+    if snapshot.version != SNAPSHOT_VERSION {
+        bail!("unsupported snapshot version: {}", snapshot.version);
+    }
+    if snapshot
+        .log
+        .windows(2)
+        .any(|w| w[0].seq >= w[1].seq || w[0].timestamp > w[1].timestamp)
+    {
+        bail!("the log is not in order");
+    }
+    if snapshot.vv.get(snapshot.id) > snapshot.clock {
+        bail!(
+            "clock {} of site {} is behind its operations",
+            snapshot.clock,
+            snapshot.id
+        );
+    }
+
+    let mut site = Site {
+        id: snapshot.id,
+        clock: snapshot.clock,
+        seq: snapshot.seq,
+        pool: snapshot.pool,
+        undo_stack: snapshot.undo_stack,
+        redo_stack: snapshot.redo_stack,
+        vv: snapshot.vv,
+        acks: snapshot.acks,
+        touched: HashMap::new(),
+        log: Vec::new(),
+        patches: Vec::new(),
+        integration: snapshot.integration,
+    };
+    // the tombstones are stable once the operations in the log which touched them are acknowledged
+    for entry in &snapshot.log {
+        site.touch(&entry.operation);
+    }
+    site.log = snapshot.log;
+    Ok(site)
 }
 
-pub fn new_site(id: i64, clock: i64) -> Site {
-    return Site {
+// the operations of a site take consecutive clocks from 1,
+// and the peers wait for the missing ones before integrating the later ones.
+pub fn new_site<T: Clone + Default>(id: i64) -> Site<T> {
+    Site {
         id,
-        clock,
+        clock: 0,
         seq: new_sequence(),
-    };
+        pool: Vec::new(),
+        undo_stack: Vec::new(),
+        redo_stack: Vec::new(),
+        vv: VersionVector::new(),
+        acks: HashMap::new(),
+        touched: HashMap::new(),
+        log: Vec::new(),
+        patches: Vec::new(),
+        integration: Integration::Classic,
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct Operation {
-    pub op: String,
-    pub c: Character,
-    pub arg1: Option<Character>,
-    pub arg2: Option<Character>,
+// section 3.2, Operations in the paper (https://hal.inria.fr/inria-00108523/document)
+// ins(c, cp, cn) inserts c between cp and cn, del(c) hides c.
+// every operation takes a clock of the site which generated it: insertions the clocks of their characters,
+// and the others the clock of their id.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(
+    into = "VersionedOperation<T>",
+    try_from = "VersionedOperation<T>",
+    bound(
+        serialize = "T: Clone + Serialize",
+        deserialize = "T: Deserialize<'de>"
+    )
+)]
+pub enum Operation<T = String> {
+    Insert {
+        c: Character<T>,
+        prev: Character<T>,
+        next: Character<T>,
+    },
+    // a run of characters typed at once by one site.
+    // the i-th character has the clock id.ng + i and is inserted between the (i-1)-th one (or prev) and next,
+    // so integrating a run is the same as integrating its characters one by one.
+    InsertRun {
+        id: ID,
+        chars: Vec<T>,
+        prev: Character<T>,
+        next: Character<T>,
+    },
+    Delete {
+        id: ID,
+        c: Character<T>,
+    },
+    // hide several characters at once. some of them may be already hidden by other sites.
+    DeleteRange {
+        id: ID,
+        ids: Vec<ID>,
+    },
+    // increase the visibility degree of characters, which undoes a deletion or redoes an insertion
+    Reveal {
+        id: ID,
+        ids: Vec<ID>,
+    },
+    // set or remove a formatting attribute over a range of characters
+    Mark(Mark),
+    // withdraw a mark, which undoes it. the formatting falls back to the other marks.
+    RetractMark {
+        id: ID,
+        mark: ID,
+    },
+    // put a retracted mark back, which redoes it
+    RestoreMark {
+        id: ID,
+        mark: ID,
+    },
 }
 
-impl Site {
-    pub fn countup(&mut self) {
-        self.clock += 1;
+impl<T> Operation<T> {
+    // the identifier of the operation (of its first character for insertions)
+    pub fn id(&self) -> ID {
+        match self {
+            Operation::Insert { c, .. } => c.id,
+            Operation::InsertRun { id, .. }
+            | Operation::Delete { id, .. }
+            | Operation::DeleteRange { id, .. }
+            | Operation::Reveal { id, .. }
+            | Operation::RetractMark { id, .. }
+            | Operation::RestoreMark { id, .. } => *id,
+            Operation::Mark(mark) => mark.id,
+        }
     }
-    pub fn execute(&mut self, operation: Operation) -> anyhow::Result<(Operation)> {
-        if operation.op == "INS" {
-            let cp = operation.arg1.context("no arg1")?;
-            let cn = operation.arg2.context("no arg1")?;
-            return self.integrate_ins(operation.c, &cp, &cn);
-        } else if operation.op == "DEL" {
-            return self.integrate_del(operation.c);
+
+    // the identifier with the last clock taken by the operation
+    pub fn last_id(&self) -> ID {
+        ID {
+            ns: self.id().ns,
+            ng: *self.clocks().end(),
         }
+    }
 
-        bail!("unknown operation");
+    // the clocks taken by the operation
+    pub fn clocks(&self) -> RangeInclusive<i64> {
+        let id = self.id();
+        match self {
+            Operation::InsertRun { chars, .. } => id.ng..=id.ng + chars.len() as i64 - 1,
+            _ => id.ng..=id.ng,
+        }
     }
 
-    // insert ch between S[p-1] and S[p]
-    pub fn generate_ins(&mut self, p: usize, ch: &str) -> anyhow::Result<Operation> {
-        self.clock += 1;
-        let cb = CB;
-        let ce = CE;
-        let cp = self.seq.ith_visible(p - 1).unwrap_or(cb);
-        let cn = self.seq.ith_visible(p).unwrap_or(ce);
+    // the characters whose visibility is changed by the operation
+    fn targets(&self) -> Vec<ID> {
+        match self {
+            Operation::Insert { c, .. } | Operation::Delete { c, .. } => vec![c.id],
+            Operation::InsertRun { id, chars, .. } => (0..chars.len() as i64)
+                .map(|i| ID {
+                    ns: id.ns,
+                    ng: id.ng + i,
+                })
+                .collect(),
+            Operation::DeleteRange { ids, .. } | Operation::Reveal { ids, .. } => ids.clone(),
+            Operation::Mark(_) | Operation::RetractMark { .. } | Operation::RestoreMark { .. } => {
+                Vec::new()
+            }
+        }
+    }
 
-        // println!("generate {:?} < {:?} < {:?}", cp.c, ch, cn.c);
+    // the characters which must exist to execute the operation, other than its targets
+    fn references(&self) -> Vec<ID> {
+        match self {
+            Operation::Insert { prev, next, .. } | Operation::InsertRun { prev, next, .. } => {
+                vec![prev.id, next.id]
+            }
+            Operation::Mark(mark) => vec![mark.start, mark.end],
+            _ => Vec::new(),
+        }
+    }
 
-        let c = Character {
-            id: ID {
-                ns: self.id,
-                ng: self.clock,
+    // the operation which cancels the effect of this one on the visibility degrees.
+    // insertions are undone by hiding the characters instead of removing them.
+    pub fn inverse(&self, id: ID) -> Operation<T> {
+        match self {
+            Operation::Insert { .. } | Operation::InsertRun { .. } | Operation::Reveal { .. } => {
+                Operation::DeleteRange {
+                    id,
+                    ids: self.targets(),
+                }
+            }
+            Operation::Delete { .. } | Operation::DeleteRange { .. } => Operation::Reveal {
+                id,
+                ids: self.targets(),
             },
-            c: String::from(ch),
-            visible: true,
-            prev_id: Some(cp.id),
-            next_id: Some(cn.id),
-        };
-
-        return self.integrate_ins(c, &cp, &cn);
+            // a mark is undone by retracting it rather than by the opposite mark,
+            // so that the characters get back the attributes they had before
+            Operation::Mark(Mark { id: mark, .. }) | Operation::RestoreMark { mark, .. } => {
+                Operation::RetractMark { id, mark: *mark }
+            }
+            Operation::RetractMark { mark, .. } => Operation::RestoreMark { id, mark: *mark },
+        }
     }
+}
 
-    // insert c between cp and cn
-    pub fn integrate_ins(
-        &mut self,
-        c: Character,
-        cp: &Character,
-        cn: &Character,
-    ) -> anyhow::Result<Operation> {
-        let p = self.seq.pos(cn).context(format!("cannot find {:?}", cn))?;
-        // println!("subseq {:?} and {:?}", cp.id, cn.id);
-        let subseq = self.seq.subseq(cp, cn).context("failed to get subseq")?;
-        if subseq.chars.len() == 0 {
-            self.seq.insert(&c, p).context("error")?;
-        } else {
-            // println!("----------subseq----------------------");
-            // for (_, sc) in subseq.chars.iter().enumerate() {
-            //     println!("{:?} {:?} {:?}", sc.id, sc.c, sc.visible);
-            // }
-            // println!("----------subseq----------------------");
-            let mut l = vec![cp; 1];
-            for (_, sc) in subseq.chars.iter().enumerate() {
-                let sc_prev_id = sc.prev_id.context("should not cb or ce at here")?;
-                let sc_next_id = sc.next_id.context("should not cb or ce at here")?;
+// an operation in the log of a site
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(bound(
+    serialize = "T: Clone + Serialize",
+    deserialize = "T: Deserialize<'de>"
+))]
+pub struct LogEntry<T = String> {
+    // the local sequence number, which is 1 for the first operation integrated by the site
+    pub seq: u64,
+    // milliseconds since the unix epoch when the operation was integrated
+    pub timestamp: u64,
+    pub operation: Operation<T>,
+}
 
-                // println!("sc: {:?} {:?} {:?}", sc.id, sc.c, sc.visible);
-                // println!(
-                //     "prev: {:?} cmp: {:?}",
-                //     sc_prev_id,
-                //     sc_prev_id.less_than_or_equal(&cp.id)
-                // );
-                // println!(
-                //     "next: {:?} cpm: {:?}",
-                //     sc_next_id,
-                //     cn.id.less_than_or_equal(&sc_next_id)
-                // );
-                let lowerbound = cp.id;
-                let upperbound = cn.id;
-                if sc_prev_id.less_than_or_equal(&lowerbound)
-                    && upperbound.less_than_or_equal(&sc_next_id)
-                {
-                    l.push(sc);
-                }
-            }
-            l.push(cn);
+// the current version of the wire format of Operation
+pub const OPERATION_VERSION: u32 = 1;
 
-            let mut i = 1;
-            while i < l.len() - 1 && l[i].id.less_than(&c.id) {
-                i += 1;
-            }
+// wire format of Operation.
+// e.g. {"version":1,"op":"INS","c":{..},"prev":{..},"next":{..}}
+#[derive(Serialize, Deserialize)]
+struct VersionedOperation<T> {
+    version: u32,
+    #[serde(flatten)]
+    op: OperationV1<T>,
+}
 
-            // println!("----------L----------------------");
-            // for v in l.iter() {
-            //     println!("{:?} {:?}", v.id, v.c)
-            // }
-            // println!("-----------L---------------------");
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op")]
+enum OperationV1<T> {
+    #[serde(rename = "INS")]
+    Insert {
+        c: Character<T>,
+        prev: Character<T>,
+        next: Character<T>,
+    },
+    #[serde(rename = "INS_RUN")]
+    InsertRun {
+        id: ID,
+        chars: Vec<T>,
+        prev: Character<T>,
+        next: Character<T>,
+    },
+    #[serde(rename = "DEL")]
+    Delete { id: ID, c: Character<T> },
+    #[serde(rename = "DEL_RANGE")]
+    DeleteRange { id: ID, ids: Vec<ID> },
+    #[serde(rename = "REVEAL")]
+    Reveal { id: ID, ids: Vec<ID> },
+    #[serde(rename = "MARK")]
+    Mark(Mark),
+    #[serde(rename = "RETRACT_MARK")]
+    RetractMark { id: ID, mark: ID },
+    #[serde(rename = "RESTORE_MARK")]
+    RestoreMark { id: ID, mark: ID },
+}
 
-            // println!("{:?} < {:?} < {:?} [{:?}]", l[i - 1].c, c.id, l[i].c, i);
-            return self.integrate_ins(c, l[i - 1], l[i]);
+impl<T> From<Operation<T>> for VersionedOperation<T> {
+    fn from(operation: Operation<T>) -> Self {
+        let op = match operation {
+            Operation::Insert { c, prev, next } => OperationV1::Insert { c, prev, next },
+            Operation::InsertRun {
+                id,
+                chars,
+                prev,
+                next,
+            } => OperationV1::InsertRun {
+                id,
+                chars,
+                prev,
+                next,
+            },
+            Operation::Delete { id, c } => OperationV1::Delete { id, c },
+            Operation::DeleteRange { id, ids } => OperationV1::DeleteRange { id, ids },
+            Operation::Reveal { id, ids } => OperationV1::Reveal { id, ids },
+            Operation::Mark(mark) => OperationV1::Mark(mark),
+            Operation::RetractMark { id, mark } => OperationV1::RetractMark { id, mark },
+            Operation::RestoreMark { id, mark } => OperationV1::RestoreMark { id, mark },
+        };
+        VersionedOperation {
+            version: OPERATION_VERSION,
+            op,
         }
-        Ok(Operation {
-            op: String::from("INS"),
-            c: c.clone(),
-            arg1: Some(cp.clone()),
-            arg2: Some(cn.clone()),
-        })
     }
+}
 
-    pub fn generate_del(&mut self, p: usize) -> anyhow::Result<(Operation)> {
-        let c = self
-            .seq
-            .ith_visible(p)
-            .context(format!("seq[{:?}] does not exist or is not visible", p))?;
-        return self.integrate_del(c);
-    }
+impl<T> TryFrom<VersionedOperation<T>> for Operation<T> {
+    type Error = anyhow::Error;
 
-    pub fn integrate_del(&mut self, c: Character) -> anyhow::Result<(Operation)> {
-        let len = self.seq.chars.len();
-        let mut it = self.seq.chars.iter_mut();
-        while let Some(elem) = it.next() {
-            if elem.id == c.id {
-                elem.visible = false;
-                return Ok(Operation {
-                    op: String::from("DEL"),
-                    c: c.clone(),
-                    arg1: None,
-                    arg2: None,
-                });
-            }
+    fn try_from(versioned: VersionedOperation<T>) -> anyhow::Result<Self> {
+        if versioned.version != OPERATION_VERSION {
+            bail!("unsupported operation version: {}", versioned.version);
         }
-        bail!("error not found {:?}", c)
+        Ok(match versioned.op {
+            OperationV1::Insert { c, prev, next } => Operation::Insert { c, prev, next },
+            OperationV1::InsertRun {
+                id,
+                chars,
+                prev,
+                next,
+            } => Operation::InsertRun {
+                id,
+                chars,
+                prev,
+                next,
+            },
+            OperationV1::Delete { id, c } => Operation::Delete { id, c },
+            OperationV1::DeleteRange { id, ids } => Operation::DeleteRange { id, ids },
+            OperationV1::Reveal { id, ids } => Operation::Reveal { id, ids },
+            OperationV1::Mark(mark) => Operation::Mark(mark),
+            OperationV1::RetractMark { id, mark } => Operation::RetractMark { id, mark },
+            OperationV1::RestoreMark { id, mark } => Operation::RestoreMark { id, mark },
+        })
     }
 }
 
-// section 3.1, Data Model in the paper (https://hal.inria.fr/inria-00108523/document)
-// definition 1
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Character {
-    pub id: ID,
-    pub c: String,
-    pub visible: bool,
-    pub prev_id: Option<ID>,
-    pub next_id: Option<ID>,
+// the current version of the snapshot format of Site
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+// snapshot format of Site. the sequence is stored with its characters including tombstones,
+// e.g. {"version":1,"id":1,"clock":3,"seq":{"chars":[{..},{..}],"marks":[],..},"pool":[],..}
+#[derive(Serialize, Deserialize)]
+#[serde(bound(
+    serialize = "T: Clone + Default + Serialize",
+    deserialize = "T: Clone + Default + Deserialize<'de>"
+))]
+struct SiteSnapshot<T> {
+    version: u32,
+    id: i64,
+    clock: i64,
+    seq: Sequence<T>,
+    pool: Vec<Operation<T>>,
+    undo_stack: Vec<Operation<T>>,
+    redo_stack: Vec<Operation<T>>,
+    vv: VersionVector,
+    acks: HashMap<i64, VersionVector>,
+    log: Vec<LogEntry<T>>,
+    #[serde(default)]
+    integration: Integration,
 }
 
-impl PartialEq for Character {
-    fn eq(&self, other: &Self) -> bool {
-        self.id == other.id
+// display names of the sites, e.g. to show who wrote what
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Authors {
+    names: BTreeMap<i64, String>,
+}
+
+impl Authors {
+    pub fn new() -> Authors {
+        Authors::default()
+    }
+
+    pub fn register(&mut self, site: i64, name: &str) {
+        self.names.insert(site, String::from(name));
+    }
+
+    // the registered name, or "site <id>" for an unknown site
+    pub fn name(&self, site: i64) -> String {
+        self.names
+            .get(&site)
+            .cloned()
+            .unwrap_or_else(|| format!("site {}", site))
     }
 }
 
-// section 3.1, Data Model in the paper (https://hal.inria.fr/inria-00108523/document)
-// definition 3
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
-pub struct ID {
-    pub ns: i64, // the identifier of a site
-    pub ng: i64, // a logical clock
+// a run of consecutive characters inserted by one site, and hidden by the same sites if deleted
+#[derive(Debug, Clone, PartialEq)]
+pub struct Blame {
+    pub author: i64,
+    pub text: String,
+    pub visible: bool,
+    pub deleted_by: Vec<i64>,
 }
 
-impl PartialEq for ID {
-    fn eq(&self, other: &Self) -> bool {
-        (self.ns == other.ns) && (self.ng == other.ng)
+impl<T: Clone + Default> Site<T> {
+    pub fn id(&self) -> i64 {
+        self.id
     }
-}
 
-impl ID {
-    pub fn less_than_or_equal(&self, other: &Self) -> bool {
-        (self.ns <= other.ns) || (self.ns == other.ns && self.ng <= other.ng)
+    pub fn clock(&self) -> i64 {
+        self.clock
     }
-    pub fn less_than(&self, other: &Self) -> bool {
-        (self.ns < other.ns) || (self.ns == other.ns && self.ng < other.ng)
+
+    pub fn integration(&self) -> Integration {
+        self.integration
     }
-}
 
-// character placed at the start
-pub const CB: Character = Character {
-    id: CB_ID,
-    c: String::new(),
-    visible: false,
-    prev_id: None,
-    next_id: None,
-};
+    // choose the algorithm for the insertions integrated from now on
+    pub fn set_integration(&mut self, integration: Integration) {
+        self.integration = integration;
+    }
 
-const CB_ID: ID = ID {
-    ns: i64::MIN,
-    ng: 0,
-};
+    // receive a remote operation.
+    // the operation is put into the pool, and then every executable operation in the pool is integrated
+    // until no more operation becomes executable.
+    // returns the operations integrated by this call (empty if the operation has to wait for others,
+    // or if it has already been integrated).
+    pub fn execute(&mut self, operation: Operation<T>) -> anyhow::Result<Vec<Operation<T>>> {
+        // duplicates (retransmissions, or copies received through several paths) are no-ops
+        if self.has_integrated(&operation) || self.pool.iter().any(|op| op.id() == operation.id()) {
+            return Ok(Vec::new());
+        }
+        self.pool.push(operation);
+        self.integrate_pool()
+    }
 
-// character placed at the end
-pub const CE: Character = Character {
-    id: CE_ID,
-    c: String::new(),
-    visible: false,
-    prev_id: None,
-    next_id: None,
-};
+    // integrate the operations in the pool until no more operation becomes ready and executable
+    fn integrate_pool(&mut self) -> anyhow::Result<Vec<Operation<T>>> {
+        let mut integrated = Vec::new();
+        while let Some(i) = self
+            .pool
+            .iter()
+            .position(|op| self.is_ready(op) && self.is_executable(op))
+        {
+            let operation = self.pool.remove(i);
+            let targets = operation.targets();
+            let visible: Vec<bool> = targets.iter().map(|id| self.is_visible(id)).collect();
+            integrated.push(self.integrate(operation)?);
 
-const CE_ID: ID = ID {
-    ns: i64::MAX,
-    ng: 0,
-};
+            let mut changed: Vec<(usize, ID)> = targets
+                .into_iter()
+                .zip(visible)
+                .filter(|(id, visible)| self.is_visible(id) != *visible)
+                .filter_map(|(id, _)| Some((self.seq.id_pos(&id)?, id)))
+                .collect();
+            changed.sort();
+            for (_, id) in changed {
+                self.push_patch(&id);
+            }
 
-#[derive(Debug)]
-pub struct Sequence {
-    chars: LinkedList<Character>,
-}
+            let vv = &self.vv;
+            self.pool.retain(|op| !vv.contains(&op.last_id()));
+        }
 
-// for subseq
-pub struct SubSequence {
-    chars: LinkedList<Character>,
-}
-impl SubSequence {
-    pub fn pos(&self, c: &Character) -> Option<usize> {
-        self.chars.iter().position(|char| *c == *char)
+        Ok(integrated)
     }
 
-    pub fn nth(&self, p: usize) -> Option<&Character> {
-        self.chars.iter().nth(p)
+    fn is_visible(&self, id: &ID) -> bool {
+        self.seq.get(id).is_some_and(|c| c.visible)
     }
-}
 
-// section 3.4, Example in the paper (https://hal.inria.fr/inria-00108523/document)
-// initial state is "cbce"
-pub fn new_sequence() -> Sequence {
-    let mut seq = Sequence {
-        chars: LinkedList::new(),
-    };
+    // report that the character has just been shown or hidden.
+    // the characters are reported in the order of the sequence, so the index is the one after the previous patches.
+    fn push_patch(&mut self, id: &ID) {
+        let (Some(c), Some(index)) = (self.seq.get(id), self.seq.visible_pos(id)) else {
+            return;
+        };
+        match (c.visible, self.patches.last_mut()) {
+            (true, Some(Patch::Insert { index: i, values })) if *i + values.len() == index => {
+                values.push(c.c.clone())
+            }
+            (true, _) => self.patches.push(Patch::Insert {
+                index,
+                values: vec![c.c.clone()],
+            }),
+            (false, Some(Patch::Delete { index: i, len })) if *i == index => *len += 1,
+            (false, _) => self.patches.push(Patch::Delete { index, len: 1 }),
+        }
+    }
 
-    seq.chars.push_back(CB);
-    seq.chars.push_back(CE);
+    // the changes of the visible sequence made by the remote operations integrated since the last call.
+    // applying them in order to a copy of the visible elements keeps it equal to the sequence,
+    // e.g. a UI can update the text incrementally instead of rendering seq.text() again.
+    pub fn take_patches(&mut self) -> Vec<Patch<T>> {
+        std::mem::take(&mut self.patches)
+    }
 
-    return seq;
-}
+    // whether the operation is covered by the version vector
+    pub fn has_integrated(&self, operation: &Operation<T>) -> bool {
+        self.vv.contains(&operation.last_id())
+    }
 
-impl Sequence {
-    pub fn text(&self) -> String {
-        let mut ret = String::new();
-        for c in self.chars.iter() {
-            if !c.visible {
-                continue;
+    // an operation is causally ready when every previous operation of its site has been integrated
+    pub fn is_ready(&self, operation: &Operation<T>) -> bool {
+        let id = operation.id();
+        *operation.clocks().start() == self.vv.get(id.ns) + 1
+    }
+
+    // the clocks of the site which this site is missing: the gaps before the operations waiting in the pool,
+    // and the operations the site has acknowledged but which have not been received.
+    pub fn missing(&self, site: i64) -> Vec<RangeInclusive<i64>> {
+        let mut known: Vec<RangeInclusive<i64>> = self
+            .pool
+            .iter()
+            .filter(|op| op.id().ns == site)
+            .map(|op| op.clocks())
+            .collect();
+        known.sort_by_key(|clocks| *clocks.start());
+
+        let mut ranges = Vec::new();
+        let mut start = self.vv.get(site) + 1;
+        for clocks in known {
+            if *clocks.start() > start {
+                ranges.push(start..=clocks.start() - 1);
             }
-            ret.push_str(&c.c)
+            start = start.max(clocks.end() + 1);
         }
-        return ret;
+        let acknowledged = self.acks.get(&site).map_or(0, |ack| ack.get(site));
+        if acknowledged >= start {
+            ranges.push(start..=acknowledged);
+        }
+        ranges
+    }
+
+    // the number of operations waiting in the pool
+    pub fn pending(&self) -> usize {
+        self.pool.len()
     }
-    pub fn pos(&self, c: &Character) -> Option<usize> {
-        self.chars.iter().position(|char| *c == *char)
+
+    // section 3.3, isExecutable in the paper (https://hal.inria.fr/inria-00108523/document)
+    // an insertion is executable if its previous and next characters exist in the sequence,
+    // and a deletion is executable if the character to delete exists in the sequence
+    // (or has already been collected, for the deletions and reveals generated by undo and redo).
+    pub fn is_executable(&self, operation: &Operation<T>) -> bool {
+        match operation {
+            Operation::Insert { prev, next, .. } | Operation::InsertRun { prev, next, .. } => {
+                self.seq.contains(prev) && self.seq.contains(next)
+            }
+            Operation::Delete { c, .. } => self.seq.contains(c),
+            Operation::DeleteRange { ids, .. } | Operation::Reveal { ids, .. } => ids
+                .iter()
+                .all(|id| self.seq.get(id).is_some() || self.is_collected(id)),
+            Operation::Mark(mark) => {
+                self.seq.get(&mark.start).is_some() && self.seq.get(&mark.end).is_some()
+            }
+            Operation::RetractMark { mark, .. } | Operation::RestoreMark { mark, .. } => {
+                self.seq.marks().iter().any(|m| m.id == *mark)
+            }
+        }
     }
 
-    pub fn insert(&mut self, ch: &Character, p: usize) -> anyhow::Result<()> {
-        match self.chars.iter().nth(p) {
-            None => Err(anyhow!("out of bounds")),
-            Some(_) => {
-                let mut tail = self.chars.split_off(p);
-                self.chars.push_back(ch.clone());
-                self.chars.append(&mut tail);
+    // whether the character was collected as garbage: it has been integrated, but is not in the sequence anymore.
+    // undos and redos of the peers may still change its degree, which is then ignored.
+    fn is_collected(&self, id: &ID) -> bool {
+        self.vv.contains(id) && self.seq.get(id).is_none()
+    }
 
-                return Ok(());
+    // integrate an operation and keep track of it in the version vector
+    fn integrate(&mut self, operation: Operation<T>) -> anyhow::Result<Operation<T>> {
+        let operation = match operation {
+            Operation::Insert { c, prev, next } => self.integrate_ins(c, &prev, &next),
+            Operation::InsertRun {
+                id,
+                chars,
+                prev,
+                next,
+            } => self.integrate_run(id, chars, prev, next),
+            Operation::Delete { id, c } => self.integrate_del(id, c),
+            Operation::DeleteRange { id, ids } => self.integrate_del_range(id, ids),
+            Operation::Reveal { id, ids } => self.integrate_reveal(id, ids),
+            Operation::Mark(mark) => self.integrate_mark(mark),
+            Operation::RetractMark { id, mark } => {
+                self.seq.retract_mark(&mark, true)?;
+                Ok(Operation::RetractMark { id, mark })
+            }
+            Operation::RestoreMark { id, mark } => {
+                self.seq.retract_mark(&mark, false)?;
+                Ok(Operation::RestoreMark { id, mark })
             }
+        }?;
+
+        let id = operation.id();
+        self.touch(&operation);
+        // operations are integrated in the order of their clocks (see is_ready)
+        let clock = *operation.clocks().end();
+        if clock > self.vv.get(id.ns) {
+            self.vv.set(id.ns, clock);
         }
+        self.push_log(operation.clone());
+
+        Ok(operation)
     }
 
-    // subseq(S, c, d) returns the part of S between the elements c and d (excluding c and d).
-    pub fn subseq(&self, c: &Character, d: &Character) -> anyhow::Result<SubSequence> {
-        let left = self
-            .chars
-            .iter()
-            .position(|char| *c == *char)
-            .context(format!("not found: {:?}", c))?;
+    // append an integrated operation to the log with the next sequence number and the current time.
+    // the timestamp does not go below the previous one even if the system clock goes back.
+    fn push_log(&mut self, operation: Operation<T>) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64)
+            .max(self.log.last().map_or(0, |e| e.timestamp));
+        self.log.push(LogEntry {
+            seq: self.log.last().map_or(0, |e| e.seq) + 1,
+            timestamp,
+            operation,
+        });
+    }
 
-        let right = self
-            .chars
-            .iter()
-            .position(|char| *d == *char)
-            .context(format!("not found: {:?}", d))?;
+    // remember the operations which changed the visibility degree of existing characters
+    fn touch(&mut self, operation: &Operation<T>) {
+        if let Operation::Delete { .. } | Operation::DeleteRange { .. } | Operation::Reveal { .. } =
+            operation
+        {
+            for target in operation.targets() {
+                if self.seq.get(&target).is_some() {
+                    self.touched.entry(target).or_default().push(operation.id());
+                }
+            }
+        }
+    }
+
+    fn next_id(&mut self) -> ID {
+        self.clock += 1;
+        ID {
+            ns: self.id,
+            ng: self.clock,
+        }
+    }
 
-        let mut chars = self.chars.clone();
-        let mut ret = chars.split_off(left + 1);
-        ret.split_off(right - chars.len());
+    // insert value between S[p-1] and S[p]
+    pub fn generate_insert(&mut self, p: usize, value: T) -> anyhow::Result<Operation<T>> {
+        if p == 0 || p > self.seq.visible_len() + 1 {
+            bail!("seq[{:?}] is out of bounds", p);
+        }
+        let cp = self.seq.ith_visible(p - 1).unwrap_or_else(Character::cb);
+        let cn = self.seq.ith_visible(p).unwrap_or_else(Character::ce);
+
+        let c = Character {
+            id: self.next_id(),
+            c: value,
+            visible: true,
+            prev_id: Some(cp.id),
+            next_id: Some(cn.id),
+            degree: 1,
+            deleted_by: Vec::new(),
+            level: cp.level.max(cn.level) + 1,
+        };
 
-        return Ok(SubSequence { chars: ret });
+        let operation = self.integrate(Operation::Insert {
+            c,
+            prev: cp,
+            next: cn,
+        })?;
+        Ok(self.record(operation))
     }
 
-    pub fn ith_visible(&self, p: usize) -> Option<Character> {
-        if p == 0 {
-            return None;
+    // insert c between cp and cn
+    pub fn integrate_ins(
+        &mut self,
+        c: Character<T>,
+        cp: &Character<T>,
+        cn: &Character<T>,
+    ) -> anyhow::Result<Operation<T>> {
+        match self.integration {
+            Integration::Classic => self.seq.integrate(&c, &cp.id, &cn.id)?,
+            Integration::Wooto => self.seq.integrate_wooto(&c, &cp.id, &cn.id)?,
         }
+        Ok(Operation::Insert {
+            c,
+            prev: cp.clone(),
+            next: cn.clone(),
+        })
+    }
 
-        let mut count = 0;
-        for c in self.chars.iter() {
-            if c.visible {
-                count += 1;
-            }
+    // insert values between S[p-1] and S[p] as a single operation
+    pub fn generate_insert_all(
+        &mut self,
+        p: usize,
+        values: Vec<T>,
+    ) -> anyhow::Result<Operation<T>> {
+        if values.is_empty() {
+            bail!("no values to insert");
+        }
+        if p == 0 || p > self.seq.visible_len() + 1 {
+            bail!("seq[{:?}] is out of bounds", p);
+        }
+        let cp = self.seq.ith_visible(p - 1).unwrap_or_else(Character::cb);
+        let cn = self.seq.ith_visible(p).unwrap_or_else(Character::ce);
 
-            if count == p {
-                return Some(c.clone());
-            }
+        let id = ID {
+            ns: self.id,
+            ng: self.clock + 1,
+        };
+        self.clock += values.len() as i64;
+
+        let operation = self.integrate(Operation::InsertRun {
+            id,
+            chars: values,
+            prev: cp,
+            next: cn,
+        })?;
+        Ok(self.record(operation))
+    }
+
+    // insert the characters of a run one by one, each after the previous one
+    pub fn integrate_run(
+        &mut self,
+        id: ID,
+        chars: Vec<T>,
+        prev: Character<T>,
+        next: Character<T>,
+    ) -> anyhow::Result<Operation<T>> {
+        let mut cp = prev.clone();
+        for c in run_characters(id, &chars, &prev, &next) {
+            self.integrate_ins(c.clone(), &cp, &next)?;
+            cp = c;
         }
 
-        return None;
+        Ok(Operation::InsertRun {
+            id,
+            chars,
+            prev,
+            next,
+        })
     }
-}
 
-mod tests {
+    pub fn generate_del(&mut self, p: usize) -> anyhow::Result<Operation<T>> {
+        let c = self
+            .seq
+            .ith_visible(p)
+            .context(format!("seq[{:?}] does not exist or is not visible", p))?;
+        let id = self.next_id();
+        let operation = self.integrate(Operation::Delete { id, c })?;
+        Ok(self.record(operation))
+    }
 
-    use crate::woot;
+    // section 3.3, IntegrateDel in the paper (https://hal.inria.fr/inria-00108523/document)
+    // deletions decrease the visibility degree instead of setting the visibility to false,
+    // so that concurrent deletions and their undos commute.
+    pub fn integrate_del(&mut self, id: ID, c: Character<T>) -> anyhow::Result<Operation<T>> {
+        if !self.seq.add_degree_by(&c.id, -1, id.ns) {
+            bail!("error not found {:?}", c.id)
+        }
+        Ok(Operation::Delete { id, c })
+    }
 
-    use super::{new_sequence, new_site};
+    // delete len visible characters from S[p]
+    pub fn generate_delete_range(&mut self, p: usize, len: usize) -> anyhow::Result<Operation<T>> {
+        if len == 0 {
+            bail!("empty range");
+        }
+        let c = self
+            .seq
+            .ith_visible(p)
+            .context(format!("seq[{:?}] does not exist or is not visible", p))?;
+        let ids: Vec<ID> = self
+            .seq
+            .iter_from(&c.id)
+            .context(format!("cannot find {:?}", c.id))?
+            .filter(|c| c.visible)
+            .take(len)
+            .map(|c| c.id)
+            .collect();
+        if ids.len() < len {
+            bail!("seq[{:?}..{:?}] is out of bounds", p, p + len);
+        }
 
-    fn site_id() -> i64 {
-        1
+        let id = self.next_id();
+        let operation = self.integrate(Operation::DeleteRange { id, ids })?;
+        Ok(self.record(operation))
     }
 
-    fn initial_seq() -> woot::Sequence {
-        new_sequence()
+    pub fn integrate_del_range(&mut self, id: ID, ids: Vec<ID>) -> anyhow::Result<Operation<T>> {
+        for target in ids.iter() {
+            if !self.seq.add_degree_by(target, -1, id.ns) && !self.is_collected(target) {
+                bail!("error not found {:?}", target)
+            }
+        }
+        Ok(Operation::DeleteRange { id, ids })
     }
 
-    fn character(s: String, site_id: i64, clock: i64) -> woot::Character {
-        woot::Character {
-            id: woot::ID {
-                ns: site_id,
-                ng: clock,
-            },
-            c: String::from("a"),
-            visible: true,
-            prev_id: None,
-            next_id: None,
+    pub fn integrate_reveal(&mut self, id: ID, ids: Vec<ID>) -> anyhow::Result<Operation<T>> {
+        for target in ids.iter() {
+            if !self.seq.add_degree_by(target, 1, id.ns) && !self.is_collected(target) {
+                bail!("error not found {:?}", target)
+            }
         }
+        Ok(Operation::Reveal { id, ids })
     }
 
-    #[test]
-    fn test_pos() {
-        let seq = initial_seq();
-        assert_eq!(seq.pos(&woot::CB).is_some_and(|p| p == 0), true);
-        assert_eq!(seq.pos(&woot::CE).is_some_and(|p| p == 1), true);
+    // set the attribute on len visible characters from S[p]
+    pub fn generate_mark(
+        &mut self,
+        p: usize,
+        len: usize,
+        attribute: Attribute,
+    ) -> anyhow::Result<Operation<T>> {
+        self.generate_mark_op(p, len, attribute, true)
+    }
 
-        let ch = character(String::from("a"), site_id(), 0);
-        assert_eq!(seq.pos(&ch).is_none(), true);
+    // remove the attribute (of any value) from len visible characters from S[p]
+    pub fn generate_unmark(
+        &mut self,
+        p: usize,
+        len: usize,
+        attribute: Attribute,
+    ) -> anyhow::Result<Operation<T>> {
+        self.generate_mark_op(p, len, attribute, false)
     }
 
-    #[test]
-    fn test_insert_and_delete() {
-        let mut site = new_site(1, 0);
-        // [cb,ce] => [cb, a, b, ce]
-        assert_eq!(site.generate_ins(1, "a").is_ok(), true);
-        assert_eq!(site.generate_ins(2, "b").is_ok(), true);
-        assert_eq!(site.seq.ith_visible(1).is_some_and(|c| c.c == "a"), true);
-        assert_eq!(site.seq.ith_visible(2).is_some_and(|c| c.c == "b"), true);
+    fn generate_mark_op(
+        &mut self,
+        p: usize,
+        len: usize,
+        attribute: Attribute,
+        set: bool,
+    ) -> anyhow::Result<Operation<T>> {
+        if len == 0 {
+            bail!("empty range");
+        }
+        let start = self
+            .seq
+            .ith_visible(p)
+            .context(format!("seq[{:?}] does not exist or is not visible", p))?;
+        let end = self.seq.ith_visible(p + len - 1).context(format!(
+            "seq[{:?}..{:?}] is out of bounds",
+            p,
+            p + len
+        ))?;
 
-        // [cb, a, b, ce] - ins(1,b) -> [cb, b, a, b, ce]
-        assert_eq!(site.generate_ins(1, "b").is_ok(), true);
-        assert_eq!(site.seq.ith_visible(1).is_some_and(|c| c.c == "b"), true);
-        assert_eq!(site.seq.ith_visible(2).is_some_and(|c| c.c == "a"), true);
-        assert_eq!(site.seq.ith_visible(3).is_some_and(|c| c.c == "b"), true);
+        // larger than the lamport clock of every mark seen so far, so that the new mark overrides them
+        let lamport = self
+            .seq
+            .marks()
+            .iter()
+            .map(|m| m.lamport)
+            .max()
+            .unwrap_or(0)
+            + 1;
+        let mark = Mark {
+            id: self.next_id(),
+            lamport,
+            start: start.id,
+            end: end.id,
+            attribute,
+            set,
+        };
+        let operation = self.integrate(Operation::Mark(mark))?;
+        Ok(self.record(operation))
+    }
 
-        // [cb, b, a, b, ce] - ins(3, c) -> [cb, b, a, c, b, ce]
-        assert_eq!(site.generate_ins(3, "c").is_ok(), true);
-        assert_eq!(site.seq.ith_visible(1).is_some_and(|c| c.c == "b"), true);
-        assert_eq!(site.seq.ith_visible(2).is_some_and(|c| c.c == "a"), true);
-        assert_eq!(site.seq.ith_visible(3).is_some_and(|c| c.c == "c"), true);
-        assert_eq!(site.seq.ith_visible(4).is_some_and(|c| c.c == "b"), true);
+    pub fn integrate_mark(&mut self, mark: Mark) -> anyhow::Result<Operation<T>> {
+        self.seq.add_mark(mark.clone())?;
+        Ok(Operation::Mark(mark))
+    }
 
-        // [cb, b, a, c, b, ce] - (del 4) -> [cb, b, a, c, ce]
-        assert_eq!(site.generate_del(4).is_ok(), true);
-        assert_eq!(site.seq.ith_visible(1).is_some_and(|c| c.c == "b"), true);
-        assert_eq!(site.seq.ith_visible(2).is_some_and(|c| c.c == "a"), true);
-        assert_eq!(site.seq.ith_visible(3).is_some_and(|c| c.c == "c"), true);
+    // revert the last local operation which is not undone yet.
+    // returns the operation to send to the other sites, or None if there is nothing to undo.
+    pub fn undo(&mut self) -> anyhow::Result<Option<Operation<T>>> {
+        let Some(operation) = self.undo_stack.pop() else {
+            return Ok(None);
+        };
+        let id = self.next_id();
+        let inverse = self.integrate(operation.inverse(id))?;
+        self.redo_stack.push(operation);
+        Ok(Some(inverse))
+    }
 
-        // [cb, b, a, c, ce] - (del 2) -> [cb, b, c, ce]
-        assert_eq!(site.generate_del(2).is_ok(), true);
-        assert_eq!(site.seq.ith_visible(1).is_some_and(|c| c.c == "b"), true);
-        assert_eq!(site.seq.ith_visible(2).is_some_and(|c| c.c == "c"), true);
-        assert_eq!(site.seq.ith_visible(3).is_none(), true);
+    // reapply the last undone operation.
+    // returns the operation to send to the other sites, or None if there is nothing to redo.
+    pub fn redo(&mut self) -> anyhow::Result<Option<Operation<T>>> {
+        let Some(operation) = self.redo_stack.pop() else {
+            return Ok(None);
+        };
+        let id = self.next_id();
+        let redo = self.integrate(operation.inverse(id).inverse(id))?;
+        self.undo_stack.push(redo.clone());
+        Ok(Some(redo))
+    }
 
-        // [b, a(not visible), c] of subseq(b,c) should be a(not visible)
-        let c1 = site.seq.ith_visible(1).unwrap();
-        let c2 = site.seq.ith_visible(2).unwrap();
-        let sub = site.seq.subseq(&c1, &c2).unwrap();
-        assert_eq!(sub.chars.len(), 1);
+    pub fn can_undo(&self) -> bool {
+        !self.undo_stack.is_empty()
+    }
 
-        // [cb, b, c, ce] - ins(2,a) -> [cb, b, c, a, ce]
-        assert_eq!(site.generate_ins(2, "a").is_ok(), true);
-        assert_eq!(site.seq.ith_visible(1).is_some_and(|c| c.c == "b"), true);
-        assert_eq!(site.seq.ith_visible(2).is_some_and(|c| c.c == "a"), true);
-        assert_eq!(site.seq.ith_visible(3).is_some_and(|c| c.c == "c"), true);
+    pub fn can_redo(&self) -> bool {
+        !self.redo_stack.is_empty()
+    }
 
-        assert_eq!(site.seq.text(), "bac");
+    pub fn version_vector(&self) -> &VersionVector {
+        &self.vv
+    }
+
+    // merge the state of another site, e.g. a document edited offline and loaded from disk.
+    // the operations integrated by the other site are regarded as integrated by this one too,
+    // and are appended to the log so that they are sent to the peers which have not integrated them.
+    pub fn merge(&mut self, other: &Site<T>) -> anyhow::Result<()> {
+        // every change of the deletions by a site comes from an operation of the site,
+        // so the side which has integrated more operations of the site knows its latest deletions
+        let vv = &self.vv;
+        self.seq.merge_with(&other.seq, |site, mine, theirs| {
+            if other.vv.get(site) > vv.get(site) {
+                theirs
+            } else {
+                mine
+            }
+        })?;
+
+        for entry in other.log.iter() {
+            if !self.vv.contains(&entry.operation.last_id()) {
+                self.touch(&entry.operation);
+                self.push_log(entry.operation.clone());
+            }
+        }
+        self.vv.merge(&other.vv);
+
+        let vv = &self.vv;
+        self.pool.retain(|op| !vv.contains(&op.last_id()));
+        self.integrate_pool()?;
+        Ok(())
+    }
+
+    // the operations integrated by this site in the order of integration
+    pub fn log(&self) -> &[LogEntry<T>] {
+        &self.log
+    }
+
+    // the operations which a peer with the version vector has not integrated yet.
+    // they are in the order of integration of this site, which is causally valid,
+    // so the peer can execute them one by one without waiting in the pool.
+    pub fn operations_since(&self, vv: &VersionVector) -> Vec<Operation<T>> {
+        self.log
+            .iter()
+            .map(|e| &e.operation)
+            .filter(|op| !vv.contains(&op.last_id()))
+            .cloned()
+            .collect()
+    }
+
+    // the sequence number of the last operation integrated at or before the timestamp, or 0 if there is none.
+    // the timestamps of the log never decrease (see push_log), so the log is searched by bisection.
+    pub fn seq_at_time(&self, timestamp: u64) -> u64 {
+        let n = self.log.partition_point(|e| e.timestamp <= timestamp);
+        n.checked_sub(1).map_or(0, |i| self.log[i].seq)
+    }
+
+    // the sequence as it was right after the operation with the sequence number was integrated,
+    // rebuilt by integrating the log up to it on a new site (0 gives the empty sequence).
+    pub fn sequence_at(&self, seq: u64) -> anyhow::Result<Sequence<T>> {
+        if seq > self.log.last().map_or(0, |e| e.seq) {
+            bail!("no operation {} in the log", seq);
+        }
+        let mut site = new_site(self.id);
+        site.integration = self.integration;
+        for entry in self.log.iter().take_while(|e| e.seq <= seq) {
+            site.integrate(entry.operation.clone())
+                .context(format!("cannot replay operation {}", entry.seq))?;
+        }
+        Ok(site.seq)
+    }
+
+    // register a peer whose acknowledgement is required before collecting tombstones
+    pub fn add_peer(&mut self, site: i64) {
+        self.acks.entry(site).or_default();
+    }
+
+    // the acknowledgement to send to the peers, which covers every operation integrated so far.
+    // the peers may collect the characters hidden by acknowledged operations,
+    // so the local deletions can not be undone (and the undone insertions can not be redone) anymore.
+    pub fn acknowledge(&mut self) -> VersionVector {
+        self.undo_stack
+            .retain(|op| !matches!(op, Operation::Delete { .. } | Operation::DeleteRange { .. }));
+        self.redo_stack.retain(|op| {
+            !matches!(
+                op,
+                Operation::Insert { .. } | Operation::InsertRun { .. } | Operation::Reveal { .. }
+            )
+        });
+        self.vv.clone()
+    }
+
+    pub fn receive_ack(&mut self, site: i64, vv: VersionVector) {
+        self.acks.entry(site).or_default().merge(&vv);
+    }
+
+    // physically remove tombstones which are causally stable, and returns the number of removed characters.
+    // a tombstone is stable when every peer has acknowledged the operations which hid it,
+    // and this site has integrated every operation the peers had generated when they acknowledged.
+    // then no peer can reveal it or generate an operation next to it anymore.
+    // tombstones still referenced as the previous or next character of another one are kept,
+    // so that integrate_ins can always find the bounds of the characters it compares.
+    // nothing is collected until the peers are known: the registered ones and every site
+    // whose operations have been integrated must have acknowledged.
+    pub fn collect_garbage(&mut self) -> usize {
+        let mut peers: Vec<i64> = self.acks.keys().copied().collect();
+        peers.extend(self.vv.iter().map(|(site, _)| site));
+        peers.retain(|&site| site != self.id);
+        peers.sort();
+        peers.dedup();
+        if peers.is_empty() {
+            return 0;
+        }
+        if self
+            .acks
+            .iter()
+            .any(|(&site, ack)| self.vv.get(site) < ack.get(site))
+        {
+            return 0;
+        }
+
+        let mut refs: HashMap<ID, usize> = HashMap::new();
+        for c in self.seq.iter() {
+            for id in [c.prev_id, c.next_id].into_iter().flatten() {
+                *refs.entry(id).or_default() += 1;
+            }
+        }
+        // the bounds of the marks are kept, so that the marks stay in place
+        for mark in self.seq.marks() {
+            for id in [mark.start, mark.end] {
+                *refs.entry(id).or_default() += 1;
+            }
+        }
+        for op in self.pool.iter() {
+            let mut ids = op.targets();
+            ids.extend(op.references());
+            for id in ids {
+                *refs.entry(id).or_default() += 1;
+            }
+        }
+
+        let stable: HashMap<ID, (Option<ID>, Option<ID>)> = self
+            .seq
+            .iter()
+            .filter(|c| !c.visible && !c.id.is_cb() && !c.id.is_ce())
+            .filter(|c| {
+                self.touched.get(&c.id).is_some_and(|ops| {
+                    ops.iter().all(|op| {
+                        peers
+                            .iter()
+                            .all(|site| self.acks.get(site).is_some_and(|ack| ack.contains(op)))
+                    })
+                })
+            })
+            .map(|c| (c.id, (c.prev_id, c.next_id)))
+            .collect();
+        let mut removable: Vec<ID> = stable
+            .keys()
+            .filter(|id| !refs.contains_key(id))
+            .copied()
+            .collect();
+
+        let mut removed = 0;
+        while let Some(id) = removable.pop() {
+            self.seq.remove(&id);
+            self.touched.remove(&id);
+            removed += 1;
+
+            // the bounds of the removed character may become unreferenced
+            let (prev_id, next_id) = stable[&id];
+            for bound in [prev_id, next_id].into_iter().flatten() {
+                if let Some(count) = refs.get_mut(&bound) {
+                    *count -= 1;
+                    if *count == 0 && stable.contains_key(&bound) {
+                        removable.push(bound);
+                    }
+                }
+            }
+        }
+
+        if removed > 0 {
+            let seq = &self.seq;
+            let exists = |op: &Operation<T>| op.targets().iter().all(|id| seq.get(id).is_some());
+            self.undo_stack.retain(exists);
+            self.redo_stack.retain(exists);
+        }
+        removed
+    }
+
+    // remember a local operation for undo. a new edit discards the undone operations.
+    fn record(&mut self, operation: Operation<T>) -> Operation<T> {
+        self.undo_stack.push(operation.clone());
+        self.redo_stack.clear();
+        operation
+    }
+}
+
+impl<T: Clone + Default + Serialize> Site<T> {
+    // write a snapshot of the site, from which load_site resumes it without reusing clocks
+    pub fn save<W: Write>(&self, writer: W) -> anyhow::Result<()> {
+        let snapshot = SiteSnapshot {
+            version: SNAPSHOT_VERSION,
+            id: self.id,
+            clock: self.clock,
+            seq: self.seq.clone(),
+            pool: self.pool.clone(),
+            undo_stack: self.undo_stack.clone(),
+            redo_stack: self.redo_stack.clone(),
+            vv: self.vv.clone(),
+            acks: self.acks.clone(),
+            log: self.log.clone(),
+            integration: self.integration,
+        };
+        serde_json::to_writer(writer, &snapshot).context("cannot write snapshot")
+    }
+}
+
+// text is a sequence of characters each of which is a grapheme cluster,
+// so that the positions are counted in user-perceived characters.
+impl Site {
+    // insert ch, which must be a single grapheme, between S[p-1] and S[p]
+    pub fn generate_ins(&mut self, p: usize, ch: &str) -> anyhow::Result<Operation> {
+        if ch.graphemes(true).count() != 1 || extends_grapheme(ch) {
+            bail!("{:?} is not a single grapheme", ch);
+        }
+        self.generate_insert(p, String::from(ch))
+    }
+
+    // insert text between S[p-1] and S[p] as a single operation
+    pub fn generate_insert_str(&mut self, p: usize, text: &str) -> anyhow::Result<Operation> {
+        if text.is_empty() {
+            bail!("empty text");
+        }
+        if extends_grapheme(text) {
+            bail!("{:?} starts in the middle of a grapheme", text);
+        }
+        self.generate_insert_all(p, text.graphemes(true).map(String::from).collect())
+    }
+
+    // the text right after the operation with the sequence number was integrated
+    pub fn text_at(&self, seq: u64) -> anyhow::Result<String> {
+        Ok(self.sequence_at(seq)?.text())
+    }
+
+    // replace the text with new_text, e.g. a file saved by an external editor,
+    // by the operations of a shortest edit script between their graphemes.
+    // returns the operations to send to the other sites.
+    pub fn apply_text(&mut self, new_text: &str) -> anyhow::Result<Vec<Operation>> {
+        let old = self.seq.values();
+        let new: Vec<String> = new_text.graphemes(true).map(String::from).collect();
+
+        // from the end, so that the positions of the earlier hunks do not change
+        let mut operations = Vec::new();
+        for (start, deleted, inserted) in diff(&old, &new).into_iter().rev() {
+            match deleted {
+                0 => {}
+                1 => operations.push(self.generate_del(start + 1)?),
+                _ => operations.push(self.generate_delete_range(start + 1, deleted)?),
+            }
+            match inserted.len() {
+                0 => {}
+                1 => operations.push(self.generate_ins(start + 1, &new[inserted.start])?),
+                _ => operations.push(self.generate_insert_str(start + 1, &new[inserted].concat())?),
+            }
+        }
+        Ok(operations)
+    }
+}
+
+// the inherent methods of Site take precedence over the ones of the trait
+impl SequenceCrdt for Site {
+    type Operation = Operation;
+    type Anchor = Anchor;
+
+    fn id(&self) -> i64 {
+        self.id
+    }
+
+    fn generate_ins(&mut self, p: usize, ch: &str) -> anyhow::Result<Operation> {
+        self.generate_ins(p, ch)
+    }
+
+    fn generate_del(&mut self, p: usize) -> anyhow::Result<Operation> {
+        self.generate_del(p)
+    }
+
+    fn execute(&mut self, operation: Operation) -> anyhow::Result<Vec<Operation>> {
+        self.execute(operation)
+    }
+
+    fn text(&self) -> String {
+        self.seq.text()
+    }
+
+    fn visible_len(&self) -> usize {
+        self.seq.visible_len()
+    }
+
+    // without cb and ce
+    fn stored_len(&self) -> usize {
+        self.seq.len() - 2
+    }
+
+    fn anchor(&self, p: usize) -> Option<Anchor> {
+        self.seq.anchor(p, Bias::After)
+    }
+
+    fn resolve(&self, anchor: &Anchor) -> Option<usize> {
+        self.seq.resolve(anchor)
+    }
+
+    fn undo(&mut self) -> anyhow::Result<Option<Operation>> {
+        self.undo()
+    }
+
+    fn redo(&mut self) -> anyhow::Result<Option<Operation>> {
+        self.redo()
+    }
+
+    fn take_patches(&mut self) -> Vec<Patch> {
+        self.take_patches()
+    }
+}
+
+// the shortest edit script from a to b by the O(ND) difference algorithm of Myers,
+// as hunks of (start in a, the number of deleted elements, the range of b inserted at start) in order.
+fn diff<T: PartialEq>(a: &[T], b: &[T]) -> Vec<(usize, usize, Range<usize>)> {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    // v[k + offset] is the furthest x reached on the diagonal k = x - y
+    let offset = n + m + 1;
+    let at = |k: isize| (k + offset) as usize;
+    // whether the path to the diagonal k comes down from k + 1 (an insertion) rather than from k - 1,
+    // where v(k) is the furthest x on the diagonal k after d - 1 edits
+    let down =
+        |v: &dyn Fn(isize) -> isize, k: isize, d: isize| k == -d || (k != d && v(k - 1) < v(k + 1));
+
+    let mut v = vec![0; at(offset) + 1];
+    // the diagonals -d..=d of v before each step d, which are all the backtracking reads,
+    // so that the trace takes O(D^2) rather than O((N+M)D) memory
+    let mut trace = Vec::new();
+    'search: for d in 0..=n + m {
+        trace.push(v[at(-d)..=at(d)].to_vec());
+        for k in (-d..=d).step_by(2) {
+            let mut x = if down(&|k| v[at(k)], k, d) {
+                v[at(k + 1)]
+            } else {
+                v[at(k - 1)] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[at(k)] = x;
+            if x >= n && y >= m {
+                break 'search;
+            }
+        }
+    }
+
+    // follow the path back from (n, m). each edit is (x, None) deleting a[x] or (x, Some(y)) inserting b[y] at x.
+    let mut edits = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for (d, window) in trace.iter().enumerate().skip(1).rev() {
+        let d = d as isize;
+        let v = |k: isize| window[(k + d) as usize];
+        let k = x - y;
+        let prev_k = if down(&v, k, d) { k + 1 } else { k - 1 };
+        let prev_x = v(prev_k);
+        let prev_y = prev_x - prev_k;
+        edits.push((
+            prev_x as usize,
+            (x - y == prev_k - 1).then_some(prev_y as usize),
+        ));
+        x = prev_x;
+        y = prev_y;
+    }
+
+    // adjacent edits make a hunk
+    let mut hunks: Vec<(usize, usize, Range<usize>)> = Vec::new();
+    for (x, inserted) in edits.into_iter().rev() {
+        match hunks.last_mut() {
+            Some((start, deleted, range)) if *start + *deleted == x => match inserted {
+                None => *deleted += 1,
+                Some(y) if range.start == range.end => *range = y..y + 1,
+                Some(_) => range.end += 1,
+            },
+            _ => hunks.push(match inserted {
+                None => (x, 1, 0..0),
+                Some(y) => (x, 0, y..y + 1),
+            }),
+        }
+    }
+    hunks
+}
+
+// whether text starts with a code point which joins the grapheme before it, e.g. a combining mark.
+// such a character would merge with its previous one in text(), and shift the positions after it.
+fn extends_grapheme(text: &str) -> bool {
+    format!("a{}", text).graphemes(true).next() != Some("a")
+}
+
+// the characters of InsertRun
+fn run_characters<T: Clone>(
+    id: ID,
+    chars: &[T],
+    prev: &Character<T>,
+    next: &Character<T>,
+) -> Vec<Character<T>> {
+    let (mut prev_id, mut prev_level) = (prev.id, prev.level);
+    chars
+        .iter()
+        .enumerate()
+        .map(|(i, ch)| {
+            let c = Character {
+                id: ID {
+                    ns: id.ns,
+                    ng: id.ng + i as i64,
+                },
+                c: ch.clone(),
+                visible: true,
+                prev_id: Some(prev_id),
+                next_id: Some(next.id),
+                degree: 1,
+                deleted_by: Vec::new(),
+                level: prev_level.max(next.level) + 1,
+            };
+            (prev_id, prev_level) = (c.id, c.level);
+            c
+        })
+        .collect()
+}
+
+// section 3.1, Data Model in the paper (https://hal.inria.fr/inria-00108523/document)
+// definition 1
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Character<T = String> {
+    pub id: ID,
+    pub c: T,
+    pub visible: bool,
+    pub prev_id: Option<ID>,
+    pub next_id: Option<ID>,
+    // visibility degree: +1 by insertion and by undoing a deletion, -1 by deletion and by undoing an insertion.
+    // the character is visible while the degree is positive.
+    #[serde(default = "default_degree")]
+    pub degree: i64,
+    // the sites whose deletions (or undos of the insertion) hide the character, in ascending order.
+    // a site appears as many times as its deletions in effect.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deleted_by: Vec<i64>,
+    // the degree of WOOTO: one more than the larger one of the previous and next characters at the generation,
+    // and 0 for cb and ce. called level here so as not to be confused with the visibility degree.
+    #[serde(default)]
+    pub level: i64,
+}
+
+fn default_degree() -> i64 {
+    1
+}
+
+impl<T> PartialEq for Character<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+// character placed at the start
+pub const CB: Character = Character {
+    id: CB_ID,
+    c: String::new(),
+    visible: false,
+    prev_id: None,
+    next_id: None,
+    degree: 0,
+    deleted_by: Vec::new(),
+    level: 0,
+};
+
+impl<T: Default> Character<T> {
+    // cb and ce holding the default element
+    pub fn cb() -> Self {
+        Character {
+            id: CB_ID,
+            c: T::default(),
+            visible: false,
+            prev_id: None,
+            next_id: None,
+            degree: 0,
+            deleted_by: Vec::new(),
+            level: 0,
+        }
+    }
+
+    pub fn ce() -> Self {
+        Character {
+            id: CE_ID,
+            c: T::default(),
+            visible: false,
+            prev_id: None,
+            next_id: None,
+            degree: 0,
+            deleted_by: Vec::new(),
+            level: 0,
+        }
+    }
+}
+
+// character placed at the end
+pub const CE: Character = Character {
+    id: CE_ID,
+    c: String::new(),
+    visible: false,
+    prev_id: None,
+    next_id: None,
+    degree: 0,
+    deleted_by: Vec::new(),
+    level: 0,
+};
+
+// the side of a character an anchor sticks to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Bias {
+    Before,
+    After,
+}
+
+// a position between two characters, tied to the character id instead of a visible index.
+// it keeps its place through remote insertions and deletions, even when the character is hidden.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Anchor {
+    pub id: ID,
+    pub bias: Bias,
+}
+
+// a formatting attribute. Link and Custom carry a value, and a character has at most one value for each key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Attribute {
+    Bold,
+    Italic,
+    Link(String),
+    Custom(String, String),
+}
+
+impl Attribute {
+    // the attributes with the same key override each other
+    pub fn key(&self) -> &str {
+        match self {
+            Attribute::Bold => "bold",
+            Attribute::Italic => "italic",
+            Attribute::Link(_) => "link",
+            Attribute::Custom(key, _) => key,
+        }
+    }
+}
+
+// an attribute set on (or removed from) the characters from start to end inclusive in the order of the sequence,
+// including the ones inserted between them later.
+// among the marks covering a character with the same key, the one with the largest (lamport, id) wins,
+// so a mark overrides every mark its site had seen, and concurrent marks are ordered by their ids.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Mark {
+    pub id: ID,
+    pub lamport: i64,
+    pub start: ID,
+    pub end: ID,
+    pub attribute: Attribute,
+    // false removes the attribute
+    pub set: bool,
+}
+
+// the sequence is stored in an order statistic tree (a treap keyed by position)
+// with an index from ID to node, so that pos, ith_visible and insert take O(log n).
+// it is serialized as its characters in order without cb and ce, and its marks (see SequenceRepr).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(
+    into = "SequenceRepr<T>",
+    try_from = "SequenceRepr<T>",
+    bound(
+        serialize = "T: Clone + Default + Serialize",
+        deserialize = "T: Clone + Default + Deserialize<'de>"
+    )
+)]
+pub struct Sequence<T = String> {
+    nodes: Vec<Node<T>>,
+    root: Option<usize>,
+    index: HashMap<ID, usize>,
+    // nodes of removed characters which can be reused
+    free: Vec<usize>,
+    // generator of the priorities of the nodes
+    rng: XorShift,
+    // formatting marks in the order of integration
+    marks: Vec<Mark>,
+    // the number of times each mark has been retracted or restored. a mark is retracted while it is odd.
+    // the retractions and restorations of a mark are made by its site one after another, so the larger number wins.
+    retractions: HashMap<ID, u64>,
+}
+
+// serialized form of Sequence, e.g. {"chars":[{..},{..}],"marks":[{..}],"retractions":[[{..},1]]}
+#[derive(Serialize, Deserialize)]
+struct SequenceRepr<T> {
+    chars: Vec<Character<T>>,
+    marks: Vec<Mark>,
+    retractions: Vec<(ID, u64)>,
+}
+
+#[derive(Debug, Clone)]
+struct Node<T> {
+    ch: Character<T>,
+    priority: u64,
+    parent: Option<usize>,
+    left: Option<usize>,
+    right: Option<usize>,
+    // the number of characters in the subtree
+    size: usize,
+    // the number of visible characters in the subtree
+    visible: usize,
+}
+
+// section 3.4, Example in the paper (https://hal.inria.fr/inria-00108523/document)
+// initial state is "cbce"
+pub fn new_sequence<T: Clone + Default>() -> Sequence<T> {
+    let mut seq = Sequence {
+        nodes: Vec::new(),
+        root: None,
+        index: HashMap::new(),
+        free: Vec::new(),
+        rng: XorShift::new(0x2545_f491_4f6c_dd1d),
+        marks: Vec::new(),
+        retractions: HashMap::new(),
+    };
+
+    seq.insert_at(Character::cb(), 0);
+    seq.insert_at(Character::ce(), 1);
+
+    seq
+}
+
+impl<T: Clone + Default> From<Sequence<T>> for Vec<Character<T>> {
+    fn from(seq: Sequence<T>) -> Self {
+        let n = seq.len();
+        seq.iter().skip(1).take(n - 2).cloned().collect()
+    }
+}
+
+impl<T: Clone + Default> From<Sequence<T>> for SequenceRepr<T> {
+    fn from(seq: Sequence<T>) -> Self {
+        let marks = seq.marks.clone();
+        let mut retractions: Vec<(ID, u64)> = seq.retractions.clone().into_iter().collect();
+        retractions.sort();
+        SequenceRepr {
+            chars: seq.into(),
+            marks,
+            retractions,
+        }
+    }
+}
+
+impl<T: Clone + Default> TryFrom<SequenceRepr<T>> for Sequence<T> {
+    type Error = anyhow::Error;
+
+    fn try_from(repr: SequenceRepr<T>) -> anyhow::Result<Self> {
+        let mut seq = Sequence::try_from(repr.chars)?;
+        for mark in repr.marks {
+            seq.add_mark(mark)?;
+        }
+        for (id, n) in repr.retractions {
+            if !seq.marks.iter().any(|m| m.id == id) {
+                bail!("cannot find mark {:?}", id);
+            }
+            seq.retractions.insert(id, n);
+        }
+        Ok(seq)
+    }
+}
+
+impl<T: Clone + Default> TryFrom<Vec<Character<T>>> for Sequence<T> {
+    type Error = anyhow::Error;
+
+    fn try_from(chars: Vec<Character<T>>) -> anyhow::Result<Self> {
+        let mut seq = new_sequence();
+        for (i, c) in chars.into_iter().enumerate() {
+            if c.id.is_cb() || c.id.is_ce() || c.visible != (c.degree > 0) {
+                bail!("invalid character {:?}", c.id);
+            }
+            seq.insert(&c, i + 1)?;
+        }
+        Ok(seq)
+    }
+}
+
+impl Sequence {
+    pub fn text(&self) -> String {
+        let mut ret = String::new();
+        for c in self.iter() {
+            if !c.visible {
+                continue;
+            }
+            ret.push_str(&c.c)
+        }
+        ret
+    }
+
+    // the visible text split into runs of characters with the same attributes
+    pub fn spans(&self) -> Vec<(String, Vec<Attribute>)> {
+        let mut spans: Vec<(String, Vec<Attribute>)> = Vec::new();
+        for (c, attributes) in self.formatted() {
+            match spans.last_mut() {
+                Some((text, last)) if *last == attributes => text.push_str(&c.c),
+                _ => spans.push((c.c.clone(), attributes)),
+            }
+        }
+        spans
+    }
+
+    // the visible text split into runs of the sites which inserted them
+    pub fn blame(&self) -> Vec<Blame> {
+        self.blame_runs(self.iter().filter(|c| c.visible))
+    }
+
+    // the text including the deleted characters split into runs, with the sites which deleted them
+    pub fn blame_all(&self) -> Vec<Blame> {
+        self.blame_runs(self.iter().filter(|c| !c.id.is_cb() && !c.id.is_ce()))
+    }
+
+    fn blame_runs<'a>(&self, chars: impl Iterator<Item = &'a Character>) -> Vec<Blame> {
+        let mut runs: Vec<Blame> = Vec::new();
+        for c in chars {
+            match runs.last_mut() {
+                Some(run)
+                    if run.author == c.id.ns
+                        && run.visible == c.visible
+                        && run.deleted_by == c.deleted_by =>
+                {
+                    run.text.push_str(&c.c)
+                }
+                _ => runs.push(Blame {
+                    author: c.id.ns,
+                    text: c.c.clone(),
+                    visible: c.visible,
+                    deleted_by: c.deleted_by.clone(),
+                }),
+            }
+        }
+        runs
+    }
+
+    // the byte offset in text() of the visible position p, i.e. after p graphemes
+    pub fn byte_offset(&self, p: usize) -> Option<usize> {
+        self.offset(p, str::len)
+    }
+
+    // the offset in UTF-16 code units of the visible position p, as used by editors and browsers
+    pub fn utf16_offset(&self, p: usize) -> Option<usize> {
+        self.offset(p, |s| s.encode_utf16().count())
+    }
+
+    // the visible position at the byte offset in text(), which must be on a grapheme boundary
+    pub fn pos_at_byte_offset(&self, offset: usize) -> Option<usize> {
+        self.pos_at_offset(offset, str::len)
+    }
+
+    // the visible position at the offset in UTF-16 code units, which must be on a grapheme boundary
+    pub fn pos_at_utf16_offset(&self, offset: usize) -> Option<usize> {
+        self.pos_at_offset(offset, |s| s.encode_utf16().count())
+    }
+
+    fn offset(&self, p: usize, len: impl Fn(&str) -> usize) -> Option<usize> {
+        if p > self.visible_len() {
+            return None;
+        }
+        Some(
+            self.iter()
+                .filter(|c| c.visible)
+                .take(p)
+                .map(|c| len(&c.c))
+                .sum(),
+        )
+    }
+
+    fn pos_at_offset(&self, offset: usize, len: impl Fn(&str) -> usize) -> Option<usize> {
+        let mut current = 0;
+        for (p, c) in self.iter().filter(|c| c.visible).enumerate() {
+            if current >= offset {
+                return (current == offset).then_some(p);
+            }
+            current += len(&c.c);
+        }
+        (current == offset).then_some(self.visible_len())
+    }
+}
+
+impl<T: Clone + Default> Sequence<T> {
+    pub fn marks(&self) -> &[Mark] {
+        &self.marks
+    }
+
+    // add a mark whose bounds exist in the sequence. a mark already added is ignored.
+    pub fn add_mark(&mut self, mark: Mark) -> anyhow::Result<()> {
+        for id in [&mark.start, &mark.end] {
+            if self.get(id).is_none() {
+                bail!("cannot find {:?}", id);
+            }
+        }
+        if !self.marks.iter().any(|m| m.id == mark.id) {
+            self.marks.push(mark);
+        }
+        Ok(())
+    }
+
+    // withdraw (or put back if retracted is false) a mark, and returns an error if it does not exist
+    pub fn retract_mark(&mut self, id: &ID, retracted: bool) -> anyhow::Result<()> {
+        if !self.marks.iter().any(|m| m.id == *id) {
+            bail!("cannot find mark {:?}", id);
+        }
+        let n = self.retractions.entry(*id).or_default();
+        if (*n % 2 == 1) != retracted {
+            *n += 1;
+        }
+        Ok(())
+    }
+
+    pub fn is_retracted(&self, id: &ID) -> bool {
+        self.retractions.get(id).is_some_and(|n| n % 2 == 1)
+    }
+
+    // the marks in effect
+    fn active_marks(&self) -> impl Iterator<Item = &Mark> {
+        self.marks.iter().filter(|m| !self.is_retracted(&m.id))
+    }
+
+    // the attributes of the character with id, in the order of their keys
+    pub fn attributes(&self, id: &ID) -> Option<Vec<Attribute>> {
+        let p = self.id_pos(id)?;
+        let covering: Vec<&Mark> = self
+            .active_marks()
+            .filter(|m| self.id_pos(&m.start) <= Some(p) && Some(p) <= self.id_pos(&m.end))
+            .collect();
+        Some(resolve_marks(covering))
+    }
+
+    // the visible characters with their attributes in order
+    pub fn formatted(&self) -> Vec<(&Character<T>, Vec<Attribute>)> {
+        // the position ranges covered by the marks, which are sorted by their starts
+        let mut ranges: Vec<(usize, usize, &Mark)> = self
+            .active_marks()
+            .filter_map(|m| Some((self.id_pos(&m.start)?, self.id_pos(&m.end)?, m)))
+            .collect();
+        ranges.sort_by_key(|(start, _, _)| *start);
+
+        let mut ret = Vec::new();
+        let mut active: Vec<(usize, &Mark)> = Vec::new();
+        let mut next = 0;
+        for (p, c) in self.iter().enumerate() {
+            while next < ranges.len() && ranges[next].0 <= p {
+                active.push((ranges[next].1, ranges[next].2));
+                next += 1;
+            }
+            active.retain(|(end, _)| *end >= p);
+            if c.visible {
+                ret.push((c, resolve_marks(active.iter().map(|(_, m)| *m).collect())));
+            }
+        }
+        ret
+    }
+
+    // the visible elements in order
+    pub fn values(&self) -> Vec<T> {
+        self.iter()
+            .filter(|c| c.visible)
+            .map(|c| c.c.clone())
+            .collect()
+    }
+
+    // the number of characters including cb, ce and invisible ones
+    pub fn len(&self) -> usize {
+        self.size(self.root)
+    }
+
+    // no visible characters, since cb and ce are always there
+    pub fn is_empty(&self) -> bool {
+        self.visible_len() == 0
+    }
+
+    // the number of visible characters
+    pub fn visible_len(&self) -> usize {
+        self.visible(self.root)
+    }
+
+    pub fn iter(&self) -> Iter<'_, T> {
+        let mut next = self.root;
+        while let Some(left) = next.and_then(|n| self.nodes[n].left) {
+            next = Some(left);
+        }
+        Iter {
+            seq: self,
+            next,
+            end: None,
+        }
+    }
+
+    pub fn pos(&self, c: &Character<T>) -> Option<usize> {
+        self.id_pos(&c.id)
+    }
+
+    pub fn id_pos(&self, id: &ID) -> Option<usize> {
+        let mut n = *self.index.get(id)?;
+        let mut p = self.size(self.nodes[n].left);
+        while let Some(parent) = self.nodes[n].parent {
+            if self.nodes[parent].right == Some(n) {
+                p += self.size(self.nodes[parent].left) + 1;
+            }
+            n = parent;
+        }
+        Some(p)
+    }
+
+    // the number of visible characters before the one with id
+    pub fn visible_pos(&self, id: &ID) -> Option<usize> {
+        let mut n = *self.index.get(id)?;
+        let mut p = self.visible(self.nodes[n].left);
+        while let Some(parent) = self.nodes[n].parent {
+            if self.nodes[parent].right == Some(n) {
+                p += self.visible(self.nodes[parent].left) + self.nodes[parent].ch.visible as usize;
+            }
+            n = parent;
+        }
+        Some(p)
+    }
+
+    // the anchor at the visible position p, i.e. after p visible characters.
+    // it sticks to S[p] with Bias::After and to S[p+1] with Bias::Before (cb and ce at the ends).
+    pub fn anchor(&self, p: usize, bias: Bias) -> Option<Anchor> {
+        let id = match bias {
+            Bias::After if p == 0 => CB_ID,
+            Bias::After => self.ith_visible(p)?.id,
+            Bias::Before if p == self.visible_len() => CE_ID,
+            Bias::Before => self.ith_visible(p + 1)?.id,
+        };
+        Some(Anchor { id, bias })
+    }
+
+    // the current visible position of the anchor, or None if its character has been collected
+    pub fn resolve(&self, anchor: &Anchor) -> Option<usize> {
+        let p = self.visible_pos(&anchor.id)?;
+        match anchor.bias {
+            Bias::After if self.get(&anchor.id)?.visible => Some(p + 1),
+            _ => Some(p),
+        }
+    }
+
+    pub fn contains(&self, c: &Character<T>) -> bool {
+        self.index.contains_key(&c.id)
+    }
+
+    pub fn get(&self, id: &ID) -> Option<&Character<T>> {
+        self.index.get(id).map(|&n| &self.nodes[n].ch)
+    }
+
+    // S[p]
+    pub fn nth(&self, p: usize) -> Option<&Character<T>> {
+        let mut n = self.root?;
+        let mut k = p;
+        loop {
+            let left = self.size(self.nodes[n].left);
+            if k < left {
+                n = self.nodes[n].left?;
+            } else if k == left {
+                return Some(&self.nodes[n].ch);
+            } else {
+                k -= left + 1;
+                n = self.nodes[n].right?;
+            }
+        }
+    }
+
+    pub fn insert(&mut self, ch: &Character<T>, p: usize) -> anyhow::Result<()> {
+        if p >= self.len() {
+            bail!("out of bounds");
+        }
+        if self.index.contains_key(&ch.id) {
+            bail!("already exists: {:?}", ch.id);
+        }
+        self.insert_at(ch.clone(), p);
+        Ok(())
+    }
+
+    // physically remove the character
+    pub fn remove(&mut self, id: &ID) -> Option<Character<T>> {
+        let p = self.id_pos(id)?;
+        let n = self.index.remove(id)?;
+
+        let (left, right) = self.split(self.root, p);
+        let (_, right) = self.split(right, 1);
+        self.root = self.join(left, right);
+        if let Some(root) = self.root {
+            self.nodes[root].parent = None;
+        }
+
+        self.free.push(n);
+        Some(self.nodes[n].ch.clone())
+    }
+
+    // add delta to the visibility degree of the character, and returns false if it does not exist
+    pub fn add_degree(&mut self, id: &ID, delta: i64) -> bool {
+        let Some(&n) = self.index.get(id) else {
+            return false;
+        };
+        let ch = &mut self.nodes[n].ch;
+        ch.degree += delta;
+        ch.visible = ch.degree > 0;
+        let mut node = Some(n);
+        while let Some(n) = node {
+            self.update(n);
+            node = self.nodes[n].parent;
+        }
+        true
+    }
+
+    // add delta to the visibility degree of the character on behalf of the site,
+    // which records (delta < 0) or forgets (delta > 0) one of its deletions in effect
+    pub fn add_degree_by(&mut self, id: &ID, delta: i64, site: i64) -> bool {
+        let Some(&n) = self.index.get(id) else {
+            return false;
+        };
+        let deleted_by = &mut self.nodes[n].ch.deleted_by;
+        if delta < 0 {
+            let i = deleted_by.partition_point(|&s| s <= site);
+            deleted_by.insert(i, site);
+        } else if let Some(i) = deleted_by.iter().position(|&s| s == site) {
+            deleted_by.remove(i);
+        }
+        self.add_degree(id, delta)
+    }
+
+    // insert c between the characters prev and next
+    // section 3.3, IntegrateIns in the paper (https://hal.inria.fr/inria-00108523/document)
+    // the recursion of the paper is unrolled into a loop narrowing (prev, next) until they are adjacent.
+    pub fn integrate(&mut self, c: &Character<T>, prev: &ID, next: &ID) -> anyhow::Result<()> {
+        // the character has already been integrated
+        if self.contains(c) {
+            return Ok(());
+        }
+
+        let mut prev = *prev;
+        let mut next = *next;
+        loop {
+            let lowerbound = self
+                .id_pos(&prev)
+                .context(format!("cannot find {:?}", prev))?;
+            let upperbound = self
+                .id_pos(&next)
+                .context(format!("cannot find {:?}", next))?;
+            if lowerbound + 1 == upperbound {
+                return self.insert(c, upperbound).context("error");
+            }
+
+            // L = prev d0 d1 ... dm next where d0..dm are the characters of subseq(prev, next)
+            // such that CP(di) <=S prev and next <=S CN(di).
+            // c is placed between the last di smaller than c and the first one which is not.
+            let (mut l_prev, mut l_next) = (prev, next);
+            for sc in self.subseq_id(&prev, &next)? {
+                let sc_prev_id = sc.prev_id.context("should not cb or ce at here")?;
+                let sc_next_id = sc.next_id.context("should not cb or ce at here")?;
+                let sc_prev = self
+                    .id_pos(&sc_prev_id)
+                    .context(format!("cannot find {:?}", sc_prev_id))?;
+                let sc_next = self
+                    .id_pos(&sc_next_id)
+                    .context(format!("cannot find {:?}", sc_next_id))?;
+                if sc_prev > lowerbound || upperbound > sc_next {
+                    continue;
+                }
+
+                if sc.id < c.id {
+                    l_prev = sc.id;
+                } else {
+                    l_next = sc.id;
+                    break;
+                }
+            }
+
+            prev = l_prev;
+            next = l_next;
+        }
+    }
+
+    // insert c between the characters prev and next by the levels of the characters
+    // section 3, WOOTO in "Wooki: a P2P Wiki-based Collaborative Writing Tool" (https://hal.inria.fr/inria-00156190/document)
+    // instead of filtering subseq(prev, next) by the positions of the bounds of each character,
+    // only the characters with the smallest level are compared with c.
+    pub fn integrate_wooto(
+        &mut self,
+        c: &Character<T>,
+        prev: &ID,
+        next: &ID,
+    ) -> anyhow::Result<()> {
+        // the character has already been integrated
+        if self.contains(c) {
+            return Ok(());
+        }
+
+        let mut prev = *prev;
+        let mut next = *next;
+        loop {
+            let lowerbound = self
+                .id_pos(&prev)
+                .context(format!("cannot find {:?}", prev))?;
+            let upperbound = self
+                .id_pos(&next)
+                .context(format!("cannot find {:?}", next))?;
+            if lowerbound + 1 == upperbound {
+                return self.insert(c, upperbound).context("error");
+            }
+
+            let dmin = self
+                .subseq_id(&prev, &next)?
+                .map(|sc| sc.level)
+                .min()
+                .context("should not be empty")?;
+            let (mut l_prev, mut l_next) = (prev, next);
+            for sc in self.subseq_id(&prev, &next)?.filter(|sc| sc.level == dmin) {
+                if sc.id < c.id {
+                    l_prev = sc.id;
+                } else {
+                    l_next = sc.id;
+                    break;
+                }
+            }
+            prev = l_prev;
+            next = l_next;
+        }
+    }
+
+    // merge another sequence into this one without the history of operations.
+    // the characters missing in this sequence, including tombstones, are integrated as if they were inserted.
+    // the deletions in effect on a common character are merged per site, taking the larger number of them,
+    // and its visibility degree follows the change of the number of deletions.
+    pub fn merge(&mut self, other: &Sequence<T>) -> anyhow::Result<()> {
+        self.merge_with(other, |_, mine, theirs| mine.max(theirs))
+    }
+
+    // merge another sequence into this one, where count(site, mine, theirs) chooses
+    // the number of deletions by the site in effect on a common character from those of both sides.
+    pub fn merge_with(
+        &mut self,
+        other: &Sequence<T>,
+        count: impl Fn(i64, usize, usize) -> usize,
+    ) -> anyhow::Result<()> {
+        let mut pending: Vec<&Character<T>> = Vec::new();
+        for c in other.iter() {
+            let Some(ch) = self.get(&c.id) else {
+                pending.push(c);
+                continue;
+            };
+            let mut sites = [ch.deleted_by.as_slice(), c.deleted_by.as_slice()].concat();
+            sites.sort();
+            sites.dedup();
+            let mut deleted_by = Vec::new();
+            for site in sites {
+                let mine = ch.deleted_by.iter().filter(|&&s| s == site).count();
+                let theirs = c.deleted_by.iter().filter(|&&s| s == site).count();
+                deleted_by.extend(std::iter::repeat_n(site, count(site, mine, theirs)));
+            }
+            let delta = ch.deleted_by.len() as i64 - deleted_by.len() as i64;
+            let n = self.index[&c.id];
+            self.nodes[n].ch.deleted_by = deleted_by;
+            if delta != 0 {
+                self.add_degree(&c.id, delta);
+            }
+        }
+
+        // the next character of a missing one may be missing too, so integrate them until no progress is made
+        while !pending.is_empty() {
+            let before = pending.len();
+            let mut rest = Vec::new();
+            for c in pending {
+                match (c.prev_id, c.next_id) {
+                    (Some(prev), Some(next))
+                        if self.get(&prev).is_some() && self.get(&next).is_some() =>
+                    {
+                        self.integrate(c, &prev, &next)?;
+                    }
+                    _ => rest.push(c),
+                }
+            }
+            if rest.len() == before {
+                bail!(
+                    "cannot merge {} characters whose neighbours are missing",
+                    before
+                );
+            }
+            pending = rest;
+        }
+
+        for mark in other.marks.iter() {
+            self.add_mark(mark.clone())?;
+        }
+        for (id, &n) in other.retractions.iter() {
+            let retractions = self.retractions.entry(*id).or_default();
+            *retractions = n.max(*retractions);
+        }
+        Ok(())
+    }
+
+    // subseq(S, c, d) returns the part of S between the elements c and d (excluding c and d).
+    pub fn subseq(&self, c: &Character<T>, d: &Character<T>) -> anyhow::Result<Iter<'_, T>> {
+        self.subseq_id(&c.id, &d.id)
+    }
+
+    pub fn subseq_id(&self, c: &ID, d: &ID) -> anyhow::Result<Iter<'_, T>> {
+        let left = self.id_pos(c).context(format!("not found: {:?}", c))?;
+        let right = self.id_pos(d).context(format!("not found: {:?}", d))?;
+        if left >= right {
+            bail!("{:?} is not before {:?}", c, d);
+        }
+
+        Ok(Iter {
+            seq: self,
+            next: self.successor(self.index[c]),
+            end: Some(self.index[d]),
+        })
+    }
+
+    pub fn ith_visible(&self, p: usize) -> Option<Character<T>> {
+        if p == 0 || p > self.visible_len() {
+            return None;
+        }
+
+        let mut n = self.root?;
+        let mut k = p;
+        loop {
+            let left = self.visible(self.nodes[n].left);
+            if k <= left {
+                n = self.nodes[n].left?;
+                continue;
+            }
+            k -= left;
+            if self.nodes[n].ch.visible {
+                if k == 1 {
+                    return Some(self.nodes[n].ch.clone());
+                }
+                k -= 1;
+            }
+            n = self.nodes[n].right?;
+        }
+    }
+
+    // iterate over the characters from the one with id
+    pub fn iter_from(&self, id: &ID) -> Option<Iter<'_, T>> {
+        Some(Iter {
+            seq: self,
+            next: Some(*self.index.get(id)?),
+            end: None,
+        })
+    }
+
+    // the node next to n in the order of the sequence
+    fn successor(&self, n: usize) -> Option<usize> {
+        match self.nodes[n].right {
+            Some(mut m) => {
+                while let Some(left) = self.nodes[m].left {
+                    m = left;
+                }
+                Some(m)
+            }
+            None => {
+                let mut m = n;
+                loop {
+                    match self.nodes[m].parent {
+                        Some(parent) if self.nodes[parent].right == Some(m) => m = parent,
+                        parent => break parent,
+                    }
+                }
+            }
+        }
+    }
+
+    fn insert_at(&mut self, ch: Character<T>, p: usize) {
+        let priority = self.rng.next_u64();
+        let id = ch.id;
+        let node = Node {
+            visible: ch.visible as usize,
+            ch,
+            priority,
+            parent: None,
+            left: None,
+            right: None,
+            size: 1,
+        };
+        let n = match self.free.pop() {
+            Some(n) => {
+                self.nodes[n] = node;
+                n
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        };
+        self.index.insert(id, n);
+
+        let (left, right) = self.split(self.root, p);
+        let right = self.join(Some(n), right);
+        self.root = self.join(left, right);
+        if let Some(root) = self.root {
+            self.nodes[root].parent = None;
+        }
+    }
+
+    fn size(&self, n: Option<usize>) -> usize {
+        n.map_or(0, |n| self.nodes[n].size)
+    }
+
+    fn visible(&self, n: Option<usize>) -> usize {
+        n.map_or(0, |n| self.nodes[n].visible)
+    }
+
+    // recompute the counters of n from its children
+    fn update(&mut self, n: usize) {
+        let (left, right) = (self.nodes[n].left, self.nodes[n].right);
+        self.nodes[n].size = self.size(left) + self.size(right) + 1;
+        self.nodes[n].visible =
+            self.visible(left) + self.visible(right) + self.nodes[n].ch.visible as usize;
+        for child in [left, right].into_iter().flatten() {
+            self.nodes[child].parent = Some(n);
+        }
+    }
+
+    // split the tree n into the first k characters and the rest
+    fn split(&mut self, n: Option<usize>, k: usize) -> (Option<usize>, Option<usize>) {
+        let Some(n) = n else {
+            return (None, None);
+        };
+
+        let left = self.size(self.nodes[n].left);
+        if k <= left {
+            let (l, r) = self.split(self.nodes[n].left, k);
+            self.nodes[n].left = r;
+            self.update(n);
+            if let Some(l) = l {
+                self.nodes[l].parent = None;
+            }
+            (l, Some(n))
+        } else {
+            let (l, r) = self.split(self.nodes[n].right, k - left - 1);
+            self.nodes[n].right = l;
+            self.update(n);
+            if let Some(r) = r {
+                self.nodes[r].parent = None;
+            }
+            (Some(n), r)
+        }
+    }
+
+    // concatenate the trees a and b
+    fn join(&mut self, a: Option<usize>, b: Option<usize>) -> Option<usize> {
+        match (a, b) {
+            (None, n) | (n, None) => n,
+            (Some(a), Some(b)) => {
+                if self.nodes[a].priority > self.nodes[b].priority {
+                    let right = self.join(self.nodes[a].right, Some(b));
+                    self.nodes[a].right = right;
+                    self.update(a);
+                    Some(a)
+                } else {
+                    let left = self.join(Some(a), self.nodes[b].left);
+                    self.nodes[b].left = left;
+                    self.update(b);
+                    Some(b)
+                }
+            }
+        }
+    }
+}
+
+// the attributes given by the marks covering a character: the winner of each key, unless it removes the key
+fn resolve_marks(marks: Vec<&Mark>) -> Vec<Attribute> {
+    let mut winners: BTreeMap<&str, &Mark> = BTreeMap::new();
+    for mark in marks {
+        let winner = winners.entry(mark.attribute.key()).or_insert(mark);
+        if (mark.lamport, mark.id) > (winner.lamport, winner.id) {
+            *winner = mark;
+        }
+    }
+    winners
+        .into_values()
+        .filter(|m| m.set)
+        .map(|m| m.attribute.clone())
+        .collect()
+}
+
+// in-order iterator over the characters of Sequence, which stops before the node end
+pub struct Iter<'a, T> {
+    seq: &'a Sequence<T>,
+    next: Option<usize>,
+    end: Option<usize>,
+}
+
+impl<'a, T: Clone + Default> Iterator for Iter<'a, T> {
+    type Item = &'a Character<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let n = self.next.filter(|&n| Some(n) != self.end)?;
+        self.next = self.seq.successor(n);
+        Some(&self.seq.nodes[n].ch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::common::XorShift;
+    use crate::testing::Edit::{Del, DeleteRange, Ins, InsertStr, Redo, Undo};
+    use crate::testing::{random_op, Network};
+    use crate::woot;
+
+    use super::{new_sequence, new_site};
+    use serde::{Deserialize, Serialize};
+
+    fn site_id() -> i64 {
+        1
+    }
+
+    fn initial_seq() -> woot::Sequence {
+        new_sequence()
+    }
+
+    fn character(s: String, site_id: i64, clock: i64) -> woot::Character {
+        woot::Character {
+            id: woot::ID {
+                ns: site_id,
+                ng: clock,
+            },
+            c: s,
+            visible: true,
+            prev_id: None,
+            next_id: None,
+            degree: 1,
+            deleted_by: Vec::new(),
+            level: 0,
+        }
+    }
+
+    #[test]
+    fn test_pos() {
+        let seq = initial_seq();
+        assert!(seq.pos(&woot::CB).is_some_and(|p| p == 0));
+        assert!(seq.pos(&woot::CE).is_some_and(|p| p == 1));
+
+        let ch = character(String::from("a"), site_id(), 0);
+        assert!(seq.pos(&ch).is_none());
+    }
+
+    #[test]
+    fn test_insert_and_delete() {
+        let mut site = new_site(1);
+        // [cb,ce] => [cb, a, b, ce]
+        assert!(site.generate_ins(1, "a").is_ok());
+        assert!(site.generate_ins(2, "b").is_ok());
+        assert!(site.seq.ith_visible(1).is_some_and(|c| c.c == "a"));
+        assert!(site.seq.ith_visible(2).is_some_and(|c| c.c == "b"));
+
+        // [cb, a, b, ce] - ins(1,b) -> [cb, b, a, b, ce]
+        assert!(site.generate_ins(1, "b").is_ok());
+        assert!(site.seq.ith_visible(1).is_some_and(|c| c.c == "b"));
+        assert!(site.seq.ith_visible(2).is_some_and(|c| c.c == "a"));
+        assert!(site.seq.ith_visible(3).is_some_and(|c| c.c == "b"));
+
+        // [cb, b, a, b, ce] - ins(3, c) -> [cb, b, a, c, b, ce]
+        assert!(site.generate_ins(3, "c").is_ok());
+        assert!(site.seq.ith_visible(1).is_some_and(|c| c.c == "b"));
+        assert!(site.seq.ith_visible(2).is_some_and(|c| c.c == "a"));
+        assert!(site.seq.ith_visible(3).is_some_and(|c| c.c == "c"));
+        assert!(site.seq.ith_visible(4).is_some_and(|c| c.c == "b"));
+
+        // [cb, b, a, c, b, ce] - (del 4) -> [cb, b, a, c, ce]
+        assert!(site.generate_del(4).is_ok());
+        assert!(site.seq.ith_visible(1).is_some_and(|c| c.c == "b"));
+        assert!(site.seq.ith_visible(2).is_some_and(|c| c.c == "a"));
+        assert!(site.seq.ith_visible(3).is_some_and(|c| c.c == "c"));
+
+        // [cb, b, a, c, ce] - (del 2) -> [cb, b, c, ce]
+        assert!(site.generate_del(2).is_ok());
+        assert!(site.seq.ith_visible(1).is_some_and(|c| c.c == "b"));
+        assert!(site.seq.ith_visible(2).is_some_and(|c| c.c == "c"));
+        assert!(site.seq.ith_visible(3).is_none());
+
+        // [b, a(not visible), c] of subseq(b,c) should be a(not visible)
+        let c1 = site.seq.ith_visible(1).unwrap();
+        let c2 = site.seq.ith_visible(2).unwrap();
+        let sub = site.seq.subseq(&c1, &c2).unwrap();
+        assert_eq!(sub.count(), 1);
+
+        // [cb, b, c, ce] - ins(2,a) -> [cb, b, c, a, ce]
+        assert!(site.generate_ins(2, "a").is_ok());
+        assert!(site.seq.ith_visible(1).is_some_and(|c| c.c == "b"));
+        assert!(site.seq.ith_visible(2).is_some_and(|c| c.c == "a"));
+        assert!(site.seq.ith_visible(3).is_some_and(|c| c.c == "c"));
+
+        assert_eq!(site.seq.text(), "bac");
+    }
+
+    #[test]
+    fn test_execute_out_of_order() {
+        let mut site1 = new_site(1);
+        let mut site2 = new_site(2);
+
+        // site1: [cb, ce] => [cb, a, b, ce] => [cb, b, ce]
+        let ins_a = site1.generate_ins(1, "a").unwrap();
+        let ins_b = site1.generate_ins(2, "b").unwrap();
+        let del_a = site1.generate_del(1).unwrap();
+
+        // site2 receives the operations in reverse order
+        assert!(site2.execute(del_a).unwrap().is_empty());
+        assert!(site2.execute(ins_b).unwrap().is_empty());
+        assert_eq!(site2.pending(), 2);
+        assert_eq!(site2.seq.text(), "");
+
+        // once "a" arrives, every operation in the pool becomes executable
+        assert_eq!(site2.execute(ins_a).unwrap().len(), 3);
+        assert_eq!(site2.pending(), 0);
+        assert_eq!(site2.seq.text(), site1.seq.text());
+        assert_eq!(site2.seq.text(), "b");
+    }
+
+    #[test]
+    fn test_operation_serde() {
+        let mut site = new_site(1);
+        let ins = site.generate_ins(1, "a").unwrap();
+        let del = site.generate_del(1).unwrap();
+
+        for op in [ins, del] {
+            let json = serde_json::to_string(&op).unwrap();
+            let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+            assert_eq!(value["version"], woot::OPERATION_VERSION);
+            assert_eq!(serde_json::from_str::<woot::Operation>(&json).unwrap(), op);
+        }
+
+        let json = serde_json::to_string(&site.generate_ins(1, "b").unwrap()).unwrap();
+        let mut value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["op"], "INS");
+
+        // an insertion without its neighbours cannot be built
+        value.as_object_mut().unwrap().remove("prev");
+        assert!(serde_json::from_value::<woot::Operation>(value.clone()).is_err());
+
+        // unknown versions are rejected
+        let mut value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        value["version"] = serde_json::json!(woot::OPERATION_VERSION + 1);
+        assert!(serde_json::from_value::<woot::Operation>(value).is_err());
+    }
+
+    #[test]
+    fn test_id_order() {
+        let a1 = woot::ID { ns: 1, ng: 1 };
+        let a2 = woot::ID { ns: 1, ng: 2 };
+        let b1 = woot::ID { ns: 2, ng: 1 };
+
+        assert!(a1 < a2);
+        assert!(a2 > a1);
+        assert!(a2 < b1);
+        assert_eq!(a1.cmp(&a1), std::cmp::Ordering::Equal);
+        assert!(woot::CB.id < a1 && woot::CB.id < woot::CE.id);
+        assert!(woot::CE.id > b1);
+
+        // sentinels stay at both ends even for extreme site ids
+        let min = woot::ID {
+            ns: i64::MIN,
+            ng: -1,
+        };
+        let max = woot::ID {
+            ns: i64::MAX,
+            ng: 1,
+        };
+        assert!(woot::CB.id < min);
+        assert!(max < woot::CE.id);
+    }
+
+    fn permutations(n: usize) -> Vec<Vec<usize>> {
+        if n == 0 {
+            return vec![vec![]];
+        }
+        let mut ret = Vec::new();
+        for p in permutations(n - 1) {
+            for i in 0..=p.len() {
+                let mut q = p.clone();
+                q.insert(i, n - 1);
+                ret.push(q);
+            }
+        }
+        ret
+    }
+
+    #[test]
+    fn test_concurrent_insert_at_same_position() {
+        let mut origin = new_site(1);
+        let initial = [
+            origin.generate_ins(1, "a").unwrap(),
+            origin.generate_ins(2, "b").unwrap(),
+        ];
+
+        // every site inserts between "a" and "b" concurrently
+        let mut ops = Vec::new();
+        for (id, text) in [(2, "xx"), (3, "yy"), (4, "zz"), (5, "w")] {
+            let mut site = new_site(id);
+            for op in initial.iter() {
+                site.execute(op.clone()).unwrap();
+            }
+            for (i, ch) in text.chars().enumerate() {
+                ops.push(site.generate_ins(2 + i, &ch.to_string()).unwrap());
+            }
+        }
+
+        let mut expected = None;
+        for order in permutations(ops.len()) {
+            let mut site = new_site(6);
+            for op in initial.iter() {
+                site.execute(op.clone()).unwrap();
+            }
+            for i in order {
+                site.execute(ops[i].clone()).unwrap();
+            }
+            assert_eq!(site.pending(), 0);
+
+            let text = site.seq.text();
+            match &expected {
+                None => expected = Some(text),
+                Some(e) => assert_eq!(*e, text),
+            }
+        }
+        assert_eq!(expected.unwrap(), "axxyyzzwb");
+    }
+
+    #[test]
+    fn test_sequence_index() {
+        let mut rng = XorShift::new(88172645463325252);
+        let mut seq = initial_seq();
+        assert!(seq.is_empty());
+        // model of the sequence: (id, visible)
+        let mut model = vec![(woot::CB.id, false), (woot::CE.id, false)];
+
+        for clock in 1..=2000 {
+            let p = 1 + rng.next(model.len() - 1);
+            let ch = character(String::from("a"), site_id(), clock);
+            seq.insert(&ch, p).unwrap();
+            model.insert(p, (ch.id, true));
+
+            if rng.next(3) == 0 {
+                let q = 1 + rng.next(model.len() - 2);
+                if model[q].1 {
+                    assert!(seq.add_degree(&model[q].0, -1));
+                    model[q].1 = false;
+                }
+            }
+        }
+
+        assert_eq!(seq.len(), model.len());
+        let visible: Vec<_> = model.iter().filter(|(_, v)| *v).collect();
+        assert_eq!(seq.visible_len(), visible.len());
+        assert_eq!(seq.is_empty(), visible.is_empty());
+        assert_eq!(seq.text().len(), visible.len());
+
+        for (p, (id, _)) in model.iter().enumerate() {
+            assert_eq!(seq.id_pos(id), Some(p));
+            assert_eq!(seq.nth(p).map(|c| c.id), Some(*id));
+        }
+        for (i, (id, _)) in visible.iter().enumerate() {
+            assert_eq!(seq.ith_visible(i + 1).map(|c| c.id), Some(*id));
+        }
+        assert!(seq.ith_visible(visible.len() + 1).is_none());
+
+        let ids: Vec<_> = seq.iter().map(|c| c.id).collect();
+        let expected: Vec<_> = model.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, expected);
+
+        // out of bounds and duplicated characters are rejected
+        let ch = character(String::from("a"), site_id(), 0);
+        assert!(seq.insert(&ch, seq.len()).is_err());
+        assert!(seq.insert(&seq.nth(1).unwrap().clone(), 1).is_err());
+    }
+
+    #[test]
+    fn test_integrate_long_concurrent_runs() {
+        // two sites type long runs at the same position and delete most of them concurrently
+        let mut site1 = new_site(1);
+        let mut site2 = new_site(2);
+        let mut ops1 = Vec::new();
+        let mut ops2 = Vec::new();
+        for i in 1..=1000 {
+            ops1.push(site1.generate_ins(i, "a").unwrap());
+            ops2.push(site2.generate_ins(i, "b").unwrap());
+        }
+        for _ in 0..900 {
+            ops1.push(site1.generate_del(1).unwrap());
+            ops2.push(site2.generate_del(50).unwrap());
+        }
+
+        for op in ops2 {
+            site1.execute(op).unwrap();
+        }
+        for op in ops1 {
+            site2.execute(op).unwrap();
+        }
+
+        assert_eq!(site1.seq.visible_len(), 200);
+        assert_eq!(site1.seq.text(), site2.seq.text());
+    }
+
+    #[test]
+    fn test_insert_str() {
+        let mut site1 = new_site(1);
+        let mut site2 = new_site(2);
+        let mut site3 = new_site(3);
+
+        let base = site1.generate_insert_str(1, "ab").unwrap();
+        site2.execute(base.clone()).unwrap();
+        site3.execute(base).unwrap();
+
+        // concurrent insertions between "a" and "b", as a run and character by character
+        let run = site1.generate_insert_str(2, "xyz").unwrap();
+        let singles = [
+            site2.generate_ins(2, "1").unwrap(),
+            site2.generate_ins(3, "2").unwrap(),
+        ];
+        let run2 = site3.generate_insert_str(2, "uv").unwrap();
+        assert_eq!(site1.seq.text(), "axyzb");
+
+        let json = serde_json::to_string(&run).unwrap();
+        assert_eq!(serde_json::from_str::<woot::Operation>(&json).unwrap(), run);
+
+        for op in singles.iter().chain([&run2]) {
+            site1.execute(op.clone()).unwrap();
+        }
+        for op in [&run, &run2] {
+            site2.execute(op.clone()).unwrap();
+        }
+        for op in singles.iter().rev().chain([&run]) {
+            site3.execute(op.clone()).unwrap();
+        }
+
+        assert_eq!(site3.pending(), 0);
+        assert_eq!(site1.seq.text(), site2.seq.text());
+        assert_eq!(site1.seq.text(), site3.seq.text());
+        assert_eq!(site1.seq.visible_len(), 9);
+
+        // the clock continues after the run
+        let op = site1.generate_ins(1, "c").unwrap();
+        match op {
+            woot::Operation::Insert { c, .. } => assert_eq!(c.id.ng, 6),
+            _ => panic!("unexpected operation {:?}", op),
+        }
+        assert!(site1.generate_insert_str(1, "").is_err());
+    }
+
+    #[test]
+    fn test_delete_range() {
+        let mut site1 = new_site(1);
+        let mut site2 = new_site(2);
+        site2
+            .execute(site1.generate_insert_str(1, "abcdefg").unwrap())
+            .unwrap();
+
+        // concurrent overlapping deletions: "bcd" and "def"
+        let del1 = site1.generate_delete_range(2, 3).unwrap();
+        let del2 = site2.generate_delete_range(4, 3).unwrap();
+        assert_eq!(site1.seq.text(), "aefg");
+        assert_eq!(site2.seq.text(), "abcg");
+
+        let json = serde_json::to_string(&del1).unwrap();
+        assert_eq!(
+            serde_json::from_str::<woot::Operation>(&json).unwrap(),
+            del1
+        );
+
+        site1.execute(del2).unwrap();
+        site2.execute(del1).unwrap();
+        assert_eq!(site1.seq.text(), "ag");
+        assert_eq!(site2.seq.text(), "ag");
+
+        assert!(site1.generate_delete_range(2, 1).is_ok());
+        assert!(site1.generate_delete_range(1, 2).is_err());
+        assert!(site1.generate_delete_range(1, 0).is_err());
+        assert_eq!(site1.seq.text(), "a");
+    }
+
+    #[test]
+    fn test_undo_redo() {
+        let mut site1 = new_site(1);
+        let mut site2 = new_site(2);
+        let mut send = |op: Option<woot::Operation>| {
+            site2.execute(op.unwrap()).unwrap();
+            site2.seq.text()
+        };
+
+        assert_eq!(
+            send(Some(site1.generate_insert_str(1, "abc").unwrap())),
+            "abc"
+        );
+        assert_eq!(send(Some(site1.generate_del(2).unwrap())), "ac");
+        assert_eq!(send(Some(site1.generate_ins(3, "d").unwrap())), "acd");
+
+        // undo in the reverse order
+        assert_eq!(send(site1.undo().unwrap()), "ac");
+        assert_eq!(send(site1.undo().unwrap()), "abc");
+        assert_eq!(send(site1.undo().unwrap()), "");
+        assert!(site1.undo().unwrap().is_none());
+
+        assert_eq!(send(site1.redo().unwrap()), "abc");
+        assert_eq!(send(site1.redo().unwrap()), "ac");
+        assert_eq!(site1.seq.text(), "ac");
+        assert!(site1.can_redo());
+
+        // a new edit discards the redo stack
+        assert_eq!(send(Some(site1.generate_ins(1, "x").unwrap())), "xac");
+        assert!(!site1.can_redo());
+        assert!(site1.redo().unwrap().is_none());
+        assert_eq!(send(site1.undo().unwrap()), "ac");
+        assert_eq!(site1.seq.text(), site2.seq.text());
+    }
+
+    #[test]
+    fn test_concurrent_undo_of_same_deletion() {
+        let mut site1 = new_site(1);
+        let mut site2 = new_site(2);
+        site2
+            .execute(site1.generate_insert_str(1, "abc").unwrap())
+            .unwrap();
+
+        // both sites delete "b" concurrently
+        let del1 = site1.generate_del(2).unwrap();
+        let del2 = site2.generate_del(2).unwrap();
+        site1.execute(del2).unwrap();
+        site2.execute(del1).unwrap();
+
+        // "b" stays deleted while the other deletion is not undone
+        let undo1 = site1.undo().unwrap().unwrap();
+        assert_eq!(site1.seq.text(), "ac");
+        site2.execute(undo1).unwrap();
+        assert_eq!(site2.seq.text(), "ac");
+
+        let undo2 = site2.undo().unwrap().unwrap();
+        assert_eq!(site2.seq.text(), "abc");
+        site1.execute(undo2).unwrap();
+        assert_eq!(site1.seq.text(), "abc");
+    }
+
+    #[test]
+    fn test_collect_garbage() {
+        let mut site1 = new_site(1);
+        let mut site2 = new_site(2);
+        site1.add_peer(2);
+        site2.add_peer(1);
+
+        site2
+            .execute(site1.generate_insert_str(1, "abcdef").unwrap())
+            .unwrap();
+        // "b" stays as the previous character of "c", while "def" is not referenced by anyone
+        site2.execute(site1.generate_del(2).unwrap()).unwrap();
+        site1
+            .execute(site2.generate_delete_range(3, 3).unwrap())
+            .unwrap();
+        assert_eq!(site1.seq.text(), "ac");
+        assert_eq!(site1.seq.len(), 8);
+
+        // nothing is collected until the peer acknowledges the deletions
+        assert_eq!(site1.collect_garbage(), 0);
+        let ack1 = site1.acknowledge();
+        let ack2 = site2.acknowledge();
+        site1.receive_ack(2, ack2);
+        assert_eq!(site1.collect_garbage(), 3);
+        assert_eq!(site1.seq.len(), 5);
+        assert_eq!(site1.seq.text(), "ac");
+
+        // the acknowledged deletion can not be undone anymore
+        assert!(!site2.can_undo());
+
+        // editing continues next to the collected characters, only site2 still has them
+        let ops1 = [
+            site1.generate_ins(3, "x").unwrap(),
+            site1.generate_ins(2, "y").unwrap(),
+        ];
+        let ops2 = [
+            site2.generate_ins(3, "z").unwrap(),
+            site2.generate_insert_str(1, "w").unwrap(),
+        ];
+        for op in ops2 {
+            site1.execute(op).unwrap();
+        }
+        for op in ops1 {
+            site2.execute(op).unwrap();
+        }
+        assert_eq!(site1.seq.text(), site2.seq.text());
+
+        site2.receive_ack(1, ack1);
+        assert_eq!(site2.collect_garbage(), 3);
+        assert_eq!(site1.seq.len(), site2.seq.len());
+
+        // without registered peers, nothing is collected until the sites it has heard from acknowledge
+        let mut site3 = new_site(3);
+        let mut site4 = new_site(4);
+        let ops3 = [
+            site3.generate_insert_str(1, "ab").unwrap(),
+            site3.generate_del(2).unwrap(),
+        ];
+        assert_eq!(site3.collect_garbage(), 0);
+        site3.execute(site4.generate_ins(1, "x").unwrap()).unwrap();
+        assert_eq!(site3.collect_garbage(), 0);
+        for op in ops3 {
+            site4.execute(op).unwrap();
+        }
+        site3.receive_ack(4, site4.acknowledge());
+        assert_eq!(site3.collect_garbage(), 1);
+    }
+
+    #[test]
+    fn test_undo_after_collect_garbage() {
+        let mut site1 = new_site(1);
+        let mut site2 = new_site(2);
+        site1.add_peer(2);
+        site2.add_peer(1);
+
+        site2.execute(site1.generate_ins(1, "x").unwrap()).unwrap();
+        site1.execute(site2.generate_del(1).unwrap()).unwrap();
+        site2.receive_ack(1, site1.acknowledge());
+        assert_eq!(site2.collect_garbage(), 1);
+
+        // site1 can still undo and redo its insertion of "x", which site2 has collected
+        let ops = [
+            site1.undo().unwrap().unwrap(),
+            site1.redo().unwrap().unwrap(),
+            site1.generate_ins(1, "z").unwrap(),
+        ];
+        for op in ops {
+            site2.execute(op).unwrap();
+        }
+        assert_eq!(site2.pending(), 0);
+        assert_eq!(site1.seq.text(), "z");
+        assert_eq!(site2.seq.text(), "z");
+    }
+
+    #[derive(Clone)]
+    enum Message {
+        Op(Box<woot::Operation>),
+        Ack(i64, woot::VersionVector),
+    }
+
+    #[test]
+    fn test_collect_garbage_converges() {
+        let sites: Vec<woot::Site> = (1..=3).map(new_site).collect();
+        let mut net = Network::new(sites, 2463534242);
+        for site in net.sites.iter_mut() {
+            for peer in 1..=3 {
+                if peer != site.id {
+                    site.add_peer(peer);
+                }
+            }
+        }
+        // a site which never collects garbage
+        let mut observer = new_site(4);
+        let receive = |_: usize, site: &mut woot::Site, message: Message| match message {
+            Message::Op(op) => {
+                site.execute(*op).unwrap();
+            }
+            Message::Ack(id, vv) => site.receive_ack(id, vv),
+        };
+
+        let mut collected = 0;
+        for step in 0..1500 {
+            let i = net.pick();
+            let mix = [
+                Ins,
+                Ins,
+                Ins,
+                Ins,
+                InsertStr,
+                Del,
+                Del,
+                DeleteRange,
+                Undo,
+                Redo,
+            ];
+            if let Some(op) = random_op(&mut net.sites[i], &mut net.rng, &mix) {
+                observer.execute(op.clone()).unwrap();
+                net.broadcast(i, Message::Op(Box::new(op)));
+            }
+            if step % 50 == 0 {
+                let ack = net.sites[i].acknowledge();
+                net.broadcast(i, Message::Ack(i as i64 + 1, ack));
+            }
+
+            // deliver some messages in random order
+            net.deliver_some(3, receive);
+            if step % 10 == 0 {
+                let j = net.pick();
+                collected += net.sites[j].collect_garbage();
+            }
+        }
+
+        net.deliver_all(receive);
+        let (mut sites, mut rng) = (net.sites, net.rng);
+        for site in sites.iter() {
+            assert_eq!(site.pending(), 0);
+            assert_eq!(site.seq.text(), observer.seq.text());
+        }
+
+        // once every site has acknowledged everything, the unreferenced tombstones are collected
+        let acks: Vec<_> = sites.iter_mut().map(|site| site.acknowledge()).collect();
+        for site in sites.iter_mut() {
+            for (i, ack) in acks.iter().enumerate() {
+                if i as i64 + 1 != site.id {
+                    site.receive_ack(i as i64 + 1, ack.clone());
+                }
+            }
+            collected += site.collect_garbage();
+            assert!(site.seq.len() < observer.seq.len());
+        }
+        assert!(collected > 0);
+
+        // and the sites keep converging with the site which has every tombstone
+        for i in 0..30 {
+            let len = sites[i % 3].seq.visible_len();
+            let op = sites[i % 3]
+                .generate_ins(1 + rng.next(len + 1), "e")
+                .unwrap();
+            observer.execute(op.clone()).unwrap();
+            for (j, site) in sites.iter_mut().enumerate() {
+                if j != i % 3 {
+                    site.execute(op.clone()).unwrap();
+                }
+            }
+        }
+        for site in sites.iter() {
+            assert_eq!(site.seq.text(), observer.seq.text());
+        }
+    }
+
+    #[test]
+    fn test_version_vector() {
+        let mut site1 = new_site(1);
+        let mut site2 = new_site(2);
+        let mut site3 = new_site(3);
+
+        let base = site1.generate_insert_str(1, "xy").unwrap();
+        for site in [&mut site2, &mut site3] {
+            site.execute(base.clone()).unwrap();
+        }
+        assert_eq!(site2.version_vector().get(1), 2);
+
+        // the second operation of site1 does not depend on the first one,
+        // but it waits for it to keep the version vector contiguous
+        let op1 = site1.generate_ins(2, "a").unwrap();
+        let op2 = site1.generate_ins(1, "b").unwrap();
+        let op3 = site1.generate_del(1).unwrap();
+        let op4 = site3.generate_ins(1, "c").unwrap();
+        assert!(site2.execute(op3.clone()).unwrap().is_empty());
+        assert!(site2.execute(op2.clone()).unwrap().is_empty());
+        assert_eq!(site2.execute(op4).unwrap().len(), 1);
+        assert_eq!(site2.missing(1), vec![3..=3]);
+        assert!(site2.missing(3).is_empty());
+
+        assert_eq!(site2.execute(op1.clone()).unwrap().len(), 3);
+        assert!(site2.missing(1).is_empty());
+        assert_eq!(site2.version_vector().get(1), 5);
+        assert_eq!(site2.version_vector().get(3), 1);
+        assert_eq!(site2.version_vector().get(2), 0);
+
+        // operations already integrated are ignored
+        assert!(site2.execute(op2).unwrap().is_empty());
+        assert!(site2.execute(op1).unwrap().is_empty());
+        assert_eq!(site2.pending(), 0);
+        assert_eq!(site2.seq.text(), "cxay");
+
+        // acknowledged operations which have not arrived yet are missing too
+        site3.receive_ack(1, site1.acknowledge());
+        assert_eq!(site3.missing(1), vec![3..=5]);
+    }
+
+    #[test]
+    fn test_execute_duplicates() {
+        let sites: Vec<woot::Site> = (1..=3).map(new_site).collect();
+        let mut net = Network::new(sites, 1181783497276652981);
+        let receive = |_: usize, site: &mut woot::Site, op: woot::Operation| {
+            site.execute(op).unwrap();
+        };
+        let mut ops = Vec::new();
+
+        for _ in 0..300 {
+            let i = net.pick();
+            let mix = [Ins, Ins, Ins, InsertStr, DeleteRange, Del];
+            let Some(op) = random_op(&mut net.sites[i], &mut net.rng, &mix) else {
+                continue;
+            };
+            ops.push(op.clone());
+
+            // every operation reaches the other sites twice
+            net.broadcast(i, op.clone());
+            net.broadcast(i, op);
+            net.deliver_some(4, receive);
+        }
+        net.deliver_all(receive);
+        let (sites, mut rng) = (net.sites, net.rng);
+
+        // a new site receives every operation twice in random order
+        let mut replay = new_site(4);
+        let mut duplicated: Vec<_> = ops.iter().chain(ops.iter()).cloned().collect();
+        while !duplicated.is_empty() {
+            let k = rng.next(duplicated.len());
+            replay.execute(duplicated.remove(k)).unwrap();
+        }
+
+        for site in sites.iter().chain([&replay]) {
+            assert_eq!(site.pending(), 0);
+            assert_eq!(site.seq.len(), sites[0].seq.len());
+            assert_eq!(site.seq.text(), sites[0].seq.text());
+        }
+
+        // a character which already exists is not inserted again
+        let c = replay.seq.ith_visible(1).unwrap();
+        let len = replay.seq.len();
+        replay.integrate_ins(c, &woot::CB, &woot::CE).unwrap();
+        assert_eq!(replay.seq.len(), len);
+    }
+
+    #[test]
+    fn test_operations_since() {
+        let mut site1 = new_site(1);
+        let mut site2 = new_site(2);
+        let mut site3 = new_site(3);
+
+        let base = site1.generate_insert_str(1, "abc").unwrap();
+        site2.execute(base.clone()).unwrap();
+        site3.execute(base).unwrap();
+
+        // site3 goes offline while the others keep editing
+        site2.execute(site1.generate_ins(2, "x").unwrap()).unwrap();
+        site1.execute(site2.generate_del(1).unwrap()).unwrap();
+        site1
+            .execute(site2.generate_insert_str(4, "yz").unwrap())
+            .unwrap();
+        site1.execute(site2.undo().unwrap().unwrap()).unwrap();
+        let offline = site3.generate_ins(1, "w").unwrap();
+
+        // site3 catches up with site1, and the other way around
+        let delta = site1.operations_since(site3.version_vector());
+        assert_eq!(delta.len(), 4);
+        for op in delta {
+            assert_eq!(site3.execute(op).unwrap().len(), 1);
+        }
+        let delta = site3.operations_since(site1.version_vector());
+        assert_eq!(delta, vec![offline.clone()]);
+        site1.execute(offline.clone()).unwrap();
+        site2.execute(offline).unwrap();
+
+        assert_eq!(site1.seq.text(), "wxbc");
+        assert_eq!(site3.seq.text(), site1.seq.text());
+        assert_eq!(site2.seq.text(), site1.seq.text());
+        assert!(site1.operations_since(site3.version_vector()).is_empty());
+        assert_eq!(site1.log().len(), 6);
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut site1 = new_site(1);
+        let mut site2 = new_site(2);
+        let mut reference = new_site(3);
+        let mut ops = vec![site1.generate_insert_str(1, "hello world").unwrap()];
+        site2.execute(ops[0].clone()).unwrap();
+
+        // both sites edit offline for a while
+        ops.push(site1.generate_delete_range(6, 6).unwrap());
+        ops.push(site1.generate_insert_str(6, "!").unwrap());
+        ops.push(site1.generate_ins(1, "h").unwrap());
+        ops.push(site2.generate_del(1).unwrap());
+        ops.push(site2.generate_insert_str(1, "H").unwrap());
+        ops.push(site2.generate_del(7).unwrap());
+        ops.push(site2.generate_insert_str(6, ",").unwrap());
+        ops.push(site2.undo().unwrap().unwrap());
+        ops.push(site2.generate_insert_str(11, "d").unwrap());
+        for op in ops {
+            reference.execute(op).unwrap();
+        }
+
+        let mut merged1 = site1.seq.clone();
+        merged1.merge(&site2.seq).unwrap();
+        let mut merged2 = site2.seq.clone();
+        merged2.merge(&site1.seq).unwrap();
+        assert_eq!(merged1.text(), reference.seq.text());
+        assert_eq!(merged2.text(), reference.seq.text());
+        assert_eq!(merged1.len(), reference.seq.len());
+
+        // merging the same state again changes nothing
+        merged1.merge(&merged2).unwrap();
+        assert_eq!(merged1.text(), reference.seq.text());
+        assert_eq!(merged1.len(), reference.seq.len());
+
+        // after merging sites, the operations of the other site are duplicates
+        let op = site2.generate_ins(1, "x").unwrap();
+        site1.merge(&site2).unwrap();
+        site2.merge(&site1).unwrap();
+        assert_eq!(site1.seq.text(), "hxHello!d");
+        assert_eq!(site1.seq.text(), site2.seq.text());
+        assert!(site1.execute(op).unwrap().is_empty());
+        assert_eq!(site1.seq.text(), site2.seq.text());
+
+        // the merged operations are in the log, so a peer can catch up with either site
+        for op in site1.operations_since(reference.version_vector()) {
+            reference.execute(op).unwrap();
+        }
+        assert_eq!(reference.seq.text(), site1.seq.text());
+        assert_eq!(reference.pending(), 0);
+    }
+
+    #[test]
+    fn test_merge_deletions() {
+        let mut site1 = new_site(1);
+        let mut site2 = new_site(2);
+        let mut site3 = new_site(3);
+        let ins = site1.generate_insert_str(1, "xyz").unwrap();
+        site2.execute(ins.clone()).unwrap();
+        site3.execute(ins).unwrap();
+
+        // both sites delete "y", and site3 sees both deletions
+        let del1 = site1.generate_del(2).unwrap();
+        let del2 = site2.generate_del(2).unwrap();
+        site3.execute(del1).unwrap();
+        site3.execute(del2).unwrap();
+
+        // undoing one of them after merging keeps the other one
+        site1.merge(&site2).unwrap();
+        let undo1 = site1.undo().unwrap().unwrap();
+        site3.execute(undo1).unwrap();
+        assert_eq!(site1.seq.text(), "xz");
+        assert_eq!(site3.seq.text(), "xz");
+
+        // a deletion undone by its site is not brought back by a site which has not seen the undo
+        let mut site4 = new_site(4);
+        let mut site5 = new_site(5);
+        let ins = site4.generate_insert_str(1, "ab").unwrap();
+        site5.execute(ins).unwrap();
+        site5.execute(site4.generate_del(1).unwrap()).unwrap();
+        let undo4 = site4.undo().unwrap().unwrap();
+        site4.merge(&site5).unwrap();
+        assert_eq!(site4.seq.text(), "ab");
+        site5.merge(&site4).unwrap();
+        assert!(site5.execute(undo4).unwrap().is_empty());
+        assert_eq!(site5.seq.text(), "ab");
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mut site1 = woot::new_site(1);
+        let mut site2 = woot::new_site(2);
+        site1.generate_insert_str(1, "hello").unwrap();
+        site1.generate_delete_range(2, 2).unwrap();
+        site1.generate_ins(1, "y").unwrap();
+        site1.undo().unwrap();
+        // an operation whose dependencies have not arrived stays in the pool
+        site2.generate_ins(1, "a").unwrap();
+        site1.execute(site2.generate_ins(2, "b").unwrap()).unwrap();
+        assert_eq!(site1.pending(), 1);
+
+        let mut buf = Vec::new();
+        site1.save(&mut buf).unwrap();
+        let mut loaded = woot::load_site(buf.as_slice()).unwrap();
+        assert_eq!(loaded.id(), 1);
+        assert_eq!(loaded.clock(), site1.clock());
+        assert_eq!(loaded.seq.text(), "hlo");
+        assert_eq!(loaded.seq.len(), site1.seq.len());
+        assert_eq!(loaded.version_vector(), site1.version_vector());
+        assert_eq!(loaded.log(), site1.log());
+        assert_eq!(loaded.pending(), 1);
+
+        // the loaded site goes on without reusing clocks
+        let op = loaded.redo().unwrap().unwrap();
+        assert_eq!(op.id().ng, site1.clock() + 1);
+        loaded.generate_ins(1, "x").unwrap();
+        assert_eq!(loaded.seq.text(), "xyhlo");
+        loaded.execute(site2.log()[0].operation.clone()).unwrap();
+        assert_eq!(loaded.pending(), 0);
+        assert_eq!(loaded.seq.text(), "xyhloab");
+
+        let json = String::from_utf8(buf).unwrap();
+        let json = json.replacen(
+            &format!("\"version\":{}", woot::SNAPSHOT_VERSION),
+            &format!("\"version\":{}", woot::SNAPSHOT_VERSION + 1),
+            1,
+        );
+        let result: anyhow::Result<woot::Site> = woot::load_site(json.as_bytes());
+        assert!(result.is_err());
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+    struct Todo {
+        title: String,
+        done: bool,
+    }
+
+    fn todo(title: &str) -> Todo {
+        Todo {
+            title: String::from(title),
+            done: false,
+        }
+    }
+
+    #[test]
+    fn test_generic_elements() {
+        let mut site1: woot::Site<Todo> = woot::new_site(1);
+        let mut site2: woot::Site<Todo> = woot::new_site(2);
+        let mut ops = vec![site1
+            .generate_insert_all(1, vec![todo("milk"), todo("eggs")])
+            .unwrap()];
+        site2.execute(ops[0].clone()).unwrap();
+
+        ops.push(site1.generate_insert(3, todo("bread")).unwrap());
+        ops.push(site1.generate_del(1).unwrap());
+        ops.push(site2.generate_insert(2, todo("tea")).unwrap());
+        for op in ops {
+            // elements go through the same wire format as text
+            let json = serde_json::to_string(&op).unwrap();
+            let op: woot::Operation<Todo> = serde_json::from_str(&json).unwrap();
+            site1.execute(op.clone()).unwrap();
+            site2.execute(op).unwrap();
+        }
+
+        let expected = vec![todo("tea"), todo("eggs"), todo("bread")];
+        assert_eq!(site1.seq.values(), expected);
+        assert_eq!(site2.seq.values(), expected);
+
+        let mut buf = Vec::new();
+        site1.save(&mut buf).unwrap();
+        let loaded: woot::Site<Todo> = woot::load_site(buf.as_slice()).unwrap();
+        assert_eq!(loaded.seq.values(), expected);
+    }
+
+    #[test]
+    fn test_grapheme_positions() {
+        let mut site = woot::new_site(1);
+        // "e" with a combining accent, a family emoji joined by ZWJ, and an ascii letter
+        site.generate_insert_str(1, "e\u{301}\u{1F468}\u{200D}\u{1F469}x")
+            .unwrap();
+        assert_eq!(site.seq.visible_len(), 3);
+        assert!(site.generate_ins(1, "ab").is_err());
+        // a lone combining mark would merge with the grapheme before it
+        assert!(site.generate_ins(2, "\u{301}").is_err());
+        assert!(site.generate_insert_str(2, "\u{301}y").is_err());
+        site.generate_ins(3, "\u{1F600}").unwrap();
+        assert_eq!(
+            site.seq.text(),
+            "e\u{301}\u{1F468}\u{200D}\u{1F469}\u{1F600}x"
+        );
+
+        let bytes: Vec<_> = (0..=4).map(|p| site.seq.byte_offset(p).unwrap()).collect();
+        assert_eq!(bytes, vec![0, 3, 14, 18, 19]);
+        let utf16: Vec<_> = (0..=4).map(|p| site.seq.utf16_offset(p).unwrap()).collect();
+        assert_eq!(utf16, vec![0, 2, 7, 9, 10]);
+        assert_eq!(site.seq.byte_offset(5), None);
+
+        for p in 0..=4 {
+            assert_eq!(site.seq.pos_at_byte_offset(bytes[p]), Some(p));
+            assert_eq!(site.seq.pos_at_utf16_offset(utf16[p]), Some(p));
+        }
+        // inside a grapheme or out of range
+        assert_eq!(site.seq.pos_at_byte_offset(1), None);
+        assert_eq!(site.seq.pos_at_utf16_offset(3), None);
+        assert_eq!(site.seq.pos_at_byte_offset(20), None);
+    }
+
+    #[test]
+    fn test_anchor() {
+        let mut local = woot::new_site(1);
+        let mut remote = woot::new_site(2);
+        remote
+            .execute(local.generate_insert_str(1, "abcd").unwrap())
+            .unwrap();
+
+        // between "b" and "c"
+        let after = local.seq.anchor(2, woot::Bias::After).unwrap();
+        let before = local.seq.anchor(2, woot::Bias::Before).unwrap();
+        assert_eq!(local.seq.resolve(&after), Some(2));
+        assert_eq!(local.seq.resolve(&before), Some(2));
+
+        // remote insertions before the anchors move them
+        local.execute(remote.generate_ins(1, "x").unwrap()).unwrap();
+        assert_eq!(local.seq.resolve(&after), Some(3));
+        assert_eq!(local.seq.resolve(&before), Some(3));
+
+        // an insertion at the anchors goes after the one sticking to the previous character
+        // and before the one sticking to the next character
+        local.execute(remote.generate_ins(4, "y").unwrap()).unwrap();
+        assert_eq!(local.seq.text(), "xabycd");
+        assert_eq!(local.seq.resolve(&after), Some(3));
+        assert_eq!(local.seq.resolve(&before), Some(4));
+
+        // the anchors stay in place when their characters are deleted
+        local.execute(remote.generate_del(3).unwrap()).unwrap();
+        local.execute(remote.generate_del(4).unwrap()).unwrap();
+        assert_eq!(local.seq.text(), "xayd");
+        assert_eq!(local.seq.resolve(&after), Some(2));
+        assert_eq!(local.seq.resolve(&before), Some(3));
+
+        // the ends of the sequence
+        let start = local.seq.anchor(0, woot::Bias::After).unwrap();
+        let end = local.seq.anchor(4, woot::Bias::Before).unwrap();
+        assert_eq!(start.id, woot::CB.id);
+        assert_eq!(end.id, woot::CE.id);
+        local.execute(remote.generate_ins(1, "z").unwrap()).unwrap();
+        assert_eq!(local.seq.resolve(&start), Some(0));
+        assert_eq!(local.seq.resolve(&end), Some(5));
+        assert_eq!(local.seq.anchor(6, woot::Bias::After), None);
+    }
+
+    #[test]
+    fn test_patches() {
+        let sites: Vec<woot::Site> = (1..=3).map(new_site).collect();
+        let mut net = Network::new(sites, 362436069);
+        // the text of each site maintained only by the patches of remote operations and the local edits
+        let mut mirrors: Vec<Vec<String>> = (0..3).map(|_| Vec::new()).collect();
+
+        for _ in 0..500 {
+            let i = net.pick();
+            let mix = [Ins, Ins, Ins, InsertStr, Del, DeleteRange, Undo, Redo];
+            if let Some(op) = random_op(&mut net.sites[i], &mut net.rng, &mix) {
+                mirrors[i] = net.sites[i].seq.values();
+                net.broadcast(i, op);
+            }
+
+            net.deliver_some(3, |j, site, op| {
+                site.execute(op).unwrap();
+                for patch in site.take_patches() {
+                    match patch {
+                        woot::Patch::Insert { index, values } => {
+                            mirrors[j].splice(index..index, values);
+                        }
+                        woot::Patch::Delete { index, len } => {
+                            mirrors[j].drain(index..index + len);
+                        }
+                    }
+                }
+                assert_eq!(mirrors[j].concat(), site.seq.text());
+            });
+        }
+    }
+
+    #[test]
+    fn test_apply_text() {
+        let mut site = new_site(1);
+        let mut remote = new_site(2);
+        let texts = [
+            "hello world",
+            "hallo wide world!",
+            "wide world",
+            "",
+            "e\u{301}\u{1F468}\u{200D}\u{1F469}x",
+            "xe\u{301}y",
+            "abcabba",
+            "cbabac",
+        ];
+        for text in texts {
+            let ops = site.apply_text(text).unwrap();
+            assert_eq!(site.seq.text(), text);
+            for op in ops {
+                remote.execute(op).unwrap();
+            }
+            assert_eq!(remote.seq.text(), text);
+        }
+
+        // the operations are minimal: "abcabba" -> "cbabac" takes 5 edits in 4 hunks
+        site.apply_text("abcabba").unwrap();
+        let ops = site.apply_text("cbabac").unwrap();
+        assert_eq!(ops.len(), 4);
+        assert!(site.apply_text("cbabac").unwrap().is_empty());
+
+        // every edit can be undone one by one
+        while site.can_undo() {
+            site.undo().unwrap();
+        }
+        assert_eq!(site.seq.text(), "");
+    }
+
+    #[test]
+    fn test_marks() {
+        let mut site1 = new_site(1);
+        let mut site2 = new_site(2);
+        site2
+            .execute(site1.generate_insert_str(1, "hello world").unwrap())
+            .unwrap();
+
+        // "hello" bold, and concurrently "lo wo" linked by both sites
+        let bold = site1.generate_mark(1, 5, woot::Attribute::Bold).unwrap();
+        let link1 = site1
+            .generate_mark(4, 5, woot::Attribute::Link(String::from("a")))
+            .unwrap();
+        let link2 = site2
+            .generate_mark(4, 5, woot::Attribute::Link(String::from("b")))
+            .unwrap();
+        // link1 wins, as site1 had seen its bold mark before
+        // a concurrent insertion inside the range gets the formatting
+        let ins = site2.generate_ins(3, "x").unwrap();
+
+        let json = serde_json::to_string(&bold).unwrap();
+        assert_eq!(
+            serde_json::from_str::<woot::Operation>(&json).unwrap(),
+            bold
+        );
+
+        for op in [link2, ins] {
+            site1.execute(op).unwrap();
+        }
+        for op in [link1, bold] {
+            site2.execute(op).unwrap();
+        }
+
+        let link = |s: &str| woot::Attribute::Link(String::from(s));
+        let expected = vec![
+            (String::from("hexl"), vec![woot::Attribute::Bold]),
+            (String::from("lo"), vec![woot::Attribute::Bold, link("a")]),
+            (String::from(" wo"), vec![link("a")]),
+            (String::from("rld"), vec![]),
+        ];
+        assert_eq!(site1.seq.spans(), expected);
+        assert_eq!(site2.seq.spans(), expected);
+
+        // a mark overrides the ones seen by its site, whatever the order of the site ids
+        let unbold = site1.generate_unmark(2, 3, woot::Attribute::Bold).unwrap();
+        site2.execute(unbold).unwrap();
+        let relink = site1.generate_mark(6, 1, link("c")).unwrap();
+        site2.execute(relink).unwrap();
+        let expected = vec![
+            (String::from("h"), vec![woot::Attribute::Bold]),
+            (String::from("exl"), vec![]),
+            (String::from("l"), vec![woot::Attribute::Bold, link("a")]),
+            (String::from("o"), vec![woot::Attribute::Bold, link("c")]),
+            (String::from(" wo"), vec![link("a")]),
+            (String::from("rld"), vec![]),
+        ];
+        assert_eq!(site2.seq.spans(), expected);
+
+        // undoing the marks restores the formatting
+        site2.execute(site1.undo().unwrap().unwrap()).unwrap();
+        site2.execute(site1.undo().unwrap().unwrap()).unwrap();
+        assert_eq!(
+            site2.seq.spans()[0],
+            (String::from("hexl"), vec![woot::Attribute::Bold])
+        );
+
+        // an undone mark is retracted, so the characters get back the attributes they had before it
+        let mut site3 = new_site(3);
+        let mut ops = vec![
+            site3.generate_insert_str(1, "ab").unwrap(),
+            site3.generate_mark(1, 1, woot::Attribute::Bold).unwrap(),
+            site3.generate_unmark(1, 2, woot::Attribute::Bold).unwrap(),
+            site3.undo().unwrap().unwrap(),
+        ];
+        let bold_a = vec![
+            (String::from("a"), vec![woot::Attribute::Bold]),
+            (String::from("b"), vec![]),
+        ];
+        assert_eq!(site3.seq.spans(), bold_a);
+        ops.push(site3.redo().unwrap().unwrap());
+        assert_eq!(site3.seq.spans(), vec![(String::from("ab"), vec![])]);
+        ops.push(site3.undo().unwrap().unwrap());
+        let mut site4 = new_site(4);
+        for op in ops {
+            site4.execute(op).unwrap();
+        }
+        assert_eq!(site4.seq.spans(), bold_a);
+
+        // the marks and their retractions are part of the serialized sequence
+        let json = serde_json::to_string(&site4.seq).unwrap();
+        let seq: woot::Sequence = serde_json::from_str(&json).unwrap();
+        assert_eq!(seq.spans(), bold_a);
+        let mut merged = new_sequence();
+        merged.merge(&site4.seq).unwrap();
+        assert_eq!(merged.spans(), bold_a);
+
+        // the marks survive the deletion of their bounds, a merge and a snapshot
+        site2
+            .execute(site1.generate_delete_range(1, 4).unwrap())
+            .unwrap();
+        let c = site2.seq.ith_visible(1).unwrap();
+        assert_eq!(
+            site2.seq.attributes(&c.id),
+            Some(vec![woot::Attribute::Bold, link("a")])
+        );
+        let mut merged = new_sequence();
+        merged.merge(&site2.seq).unwrap();
+        assert_eq!(merged.spans(), site2.seq.spans());
+        let mut buf = Vec::new();
+        site2.save(&mut buf).unwrap();
+        let loaded: woot::Site = woot::load_site(buf.as_slice()).unwrap();
+        assert_eq!(loaded.seq.spans(), site2.seq.spans());
+
+        assert!(site1.generate_mark(1, 0, woot::Attribute::Italic).is_err());
+        assert!(site1
+            .generate_mark(1, 100, woot::Attribute::Italic)
+            .is_err());
+    }
+
+    // three sites with the integrations edit concurrently and receive the operations of the others in random order.
+    // returns every operation and the converged text.
+    fn random_edits(
+        integrations: [woot::Integration; 3],
+        seed: u64,
+    ) -> (Vec<woot::Operation>, String) {
+        let mut sites: Vec<woot::Site> = (1..=3).map(new_site).collect();
+        for (site, integration) in sites.iter_mut().zip(integrations) {
+            site.set_integration(integration);
+        }
+        let mut net = Network::new(sites, seed);
+        let receive = |_: usize, site: &mut woot::Site, op: woot::Operation| {
+            site.execute(op).unwrap();
+        };
+        let mut ops = Vec::new();
+
+        for _ in 0..400 {
+            let i = net.pick();
+            let mix = [Ins, Ins, Ins, InsertStr, Del, Undo];
+            let Some(op) = random_op(&mut net.sites[i], &mut net.rng, &mix) else {
+                continue;
+            };
+            ops.push(op.clone());
+            net.broadcast(i, op);
+            net.deliver_some(4, receive);
+        }
+        net.deliver_all(receive);
+        for site in net.sites.iter() {
+            assert_eq!(site.pending(), 0);
+            assert_eq!(site.seq.text(), net.sites[0].seq.text());
+        }
+        (ops, net.sites[0].seq.text())
+    }
+
+    #[test]
+    fn test_integrations_converge() {
+        let (classic, wooto) = (woot::Integration::Classic, woot::Integration::Wooto);
+        let integrations = [classic, wooto];
+        for seed in [88172645463325252, 2463534242, 362436069] {
+            // every site with the same integration, and sites with different ones talking to each other
+            let mut texts = Vec::new();
+            for site_integrations in [[classic; 3], [wooto; 3], [classic, wooto, classic]] {
+                let (ops, text) = random_edits(site_integrations, seed);
+                texts.push(text.clone());
+
+                // the same operations give the same text with either integration, in any order
+                let mut rng = XorShift::new(seed);
+                for replay_integration in integrations {
+                    let mut replay = new_site(4);
+                    replay.set_integration(replay_integration);
+                    let mut ops = ops.clone();
+                    while !ops.is_empty() {
+                        let k = rng.next(ops.len());
+                        replay.execute(ops.remove(k)).unwrap();
+                    }
+                    assert_eq!(replay.pending(), 0);
+                    assert_eq!(replay.seq.text(), text);
+                }
+            }
+            // the sites make the same edits on the same texts whatever their integrations
+            assert!(texts.iter().all(|text| *text == texts[0]));
+        }
+    }
+
+    #[test]
+    fn test_time_travel() {
+        let mut alice = new_site(1);
+        let mut bob = new_site(2);
+        bob.execute(alice.generate_insert_str(1, "hello").unwrap())
+            .unwrap();
+        alice.execute(bob.generate_del(1).unwrap()).unwrap();
+        alice.generate_ins(1, "j").unwrap();
+        alice.undo().unwrap();
+        alice.generate_insert_str(5, " world").unwrap();
+        assert_eq!(alice.seq.text(), "ello world");
+
+        let seqs: Vec<_> = alice.log().iter().map(|e| e.seq).collect();
+        assert_eq!(seqs, vec![1, 2, 3, 4, 5]);
+        let texts: Vec<_> = (0..=5).map(|seq| alice.text_at(seq).unwrap()).collect();
+        assert_eq!(
+            texts,
+            vec!["", "hello", "ello", "jello", "ello", "ello world"]
+        );
+        assert!(alice.text_at(6).is_err());
+
+        // the document before the edit of bob
+        let edit = alice
+            .log()
+            .iter()
+            .find(|e| e.operation.id().ns == 2)
+            .unwrap();
+        assert_eq!(alice.text_at(edit.seq - 1).unwrap(), "hello");
+
+        let last = alice.log().last().unwrap().timestamp;
+        assert_eq!(alice.seq_at_time(last), 5);
+        assert_eq!(alice.seq_at_time(alice.log()[0].timestamp - 1), 0);
+
+        // the log goes on after a snapshot
+        let mut buf = Vec::new();
+        alice.save(&mut buf).unwrap();
+        let mut loaded: woot::Site = woot::load_site(buf.as_slice()).unwrap();
+        loaded.generate_del(1).unwrap();
+        assert_eq!(loaded.log().last().unwrap().seq, 6);
+        assert_eq!(loaded.text_at(5).unwrap(), "ello world");
+        assert_eq!(loaded.text_at(6).unwrap(), "llo world");
+
+        // the timestamps of a log never go back
+        let mut value: serde_json::Value = serde_json::from_slice(&buf).unwrap();
+        value["log"][1]["timestamp"] = serde_json::json!(last + 1);
+        let result: anyhow::Result<woot::Site> = woot::load_site(value.to_string().as_bytes());
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_blame() {
+        let mut alice = new_site(1);
+        let mut bob = new_site(2);
+        let mut authors = woot::Authors::new();
+        authors.register(1, "alice");
+        authors.register(2, "bob");
+
+        bob.execute(alice.generate_insert_str(1, "hello world").unwrap())
+            .unwrap();
+        alice
+            .execute(bob.generate_insert_str(6, ", dear").unwrap())
+            .unwrap();
+        // both delete "w" concurrently, and alice undoes her deletion later
+        let del1 = alice.generate_del(13).unwrap();
+        let del2 = bob.generate_delete_range(13, 2).unwrap();
+        alice.execute(del2).unwrap();
+        bob.execute(del1).unwrap();
+        assert_eq!(alice.seq.text(), "hello, dear rld");
+
+        let blame = |site: &woot::Site| -> Vec<(String, String)> {
+            site.seq
+                .blame()
+                .into_iter()
+                .map(|run| (authors.name(run.author), run.text))
+                .collect()
+        };
+        let expected = vec![
+            (String::from("alice"), String::from("hello")),
+            (String::from("bob"), String::from(", dear")),
+            (String::from("alice"), String::from(" rld")),
+        ];
+        assert_eq!(blame(&alice), expected);
+        assert_eq!(blame(&bob), expected);
+
+        let deleted: Vec<_> = alice
+            .seq
+            .blame_all()
+            .into_iter()
+            .filter(|run| !run.visible)
+            .map(|run| (run.text, run.deleted_by))
+            .collect();
+        assert_eq!(
+            deleted,
+            vec![
+                (String::from("w"), vec![1, 2]),
+                (String::from("o"), vec![2])
+            ]
+        );
+
+        // "w" stays deleted by bob only
+        bob.execute(alice.undo().unwrap().unwrap()).unwrap();
+        for site in [&alice, &bob] {
+            let run = &site.seq.blame_all()[3];
+            assert_eq!((run.text.as_str(), &run.deleted_by), ("wo", &vec![2]));
+        }
+        assert_eq!(authors.name(3), "site 3");
     }
 }